@@ -0,0 +1,60 @@
+//! embassy-boot bootloader for the pc-usage-monitor firmware.
+//!
+//! Flash this once, the firmware can then update itself over USB (see `host update`).
+
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use cortex_m_rt::{entry, exception};
+use embassy_boot_rp::*;
+use embassy_rp::block::ImageDef;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+
+/// Size of the flash, this has to match memory.x
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+#[link_section = ".start_block"]
+#[used]
+pub static IMAGE_DEF: ImageDef = ImageDef::secure_exe();
+
+#[link_section = ".bi_entries"]
+#[used]
+pub static PICOTOOL_ENTRIES: [embassy_rp::binary_info::EntryAddr; 2] = [
+    embassy_rp::binary_info::rp_program_name!(c"pc-usage-bootloader"),
+    embassy_rp::binary_info::rp_cargo_version!(),
+];
+
+#[entry]
+fn main() -> ! {
+    let p = embassy_rp::init(Default::default());
+
+    // The watchdog keeps running once the firmware starts. If a new firmware hangs before it
+    // feeds it, the reset brings us back here and the update gets rolled back.
+    let flash = WatchdogFlash::<FLASH_SIZE>::start(p.FLASH, p.WATCHDOG, Duration::from_secs(8));
+    let flash = Mutex::new(RefCell::new(flash));
+
+    let config = BootLoaderConfig::from_linkerfile_blocking(&flash, &flash, &flash);
+    let active_offset = config.active.offset();
+    let bl: BootLoader = BootLoader::prepare(config);
+
+    unsafe { bl.load(embassy_rp::flash::FLASH_BASE as u32 + active_offset) }
+}
+
+#[no_mangle]
+#[cfg_attr(target_os = "none", link_section = ".HardFault.user")]
+unsafe extern "C" fn HardFault() {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[exception]
+unsafe fn DefaultHandler(_: i16) -> ! {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    cortex_m::asm::udf();
+}
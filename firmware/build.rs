@@ -7,11 +7,23 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also turns the `USB_VID`, `USB_PID`, `USB_MANUFACTURER` and
+//! `USB_PRODUCT` environment variables into the constants in
+//! `src/usb_identity.rs`, so the USB identity can be changed without
+//! touching the code. Unset variables keep the defaults below.
 
 use std::env;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_VID: u16 = 0x16c0;
+const DEFAULT_PID: u16 = 0x27dd;
+const DEFAULT_MANUFACTURER: &str = "Bailey Townsend";
+const DEFAULT_PRODUCT: &str = "pc-usage-monitor";
+/// A USB string descriptor holds at most 126 UTF-16 code units
+const MAX_STRING_LEN: usize = 126;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -29,8 +41,62 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    write_usb_identity(out);
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     //println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
 }
+
+/// Writes `usb_identity.rs` to `out` from the environment
+fn write_usb_identity(out: &Path) {
+    let vid = id_from_env("USB_VID", DEFAULT_VID);
+    let pid = id_from_env("USB_PID", DEFAULT_PID);
+    let manufacturer = string_from_env("USB_MANUFACTURER", DEFAULT_MANUFACTURER);
+    let product = string_from_env("USB_PRODUCT", DEFAULT_PRODUCT);
+
+    // `{:?}` gives a properly escaped literal, for both the str and the CStr
+    let code = format!(
+        "pub const USB_VID: u16 = {vid:#06x};\n\
+         pub const USB_PID: u16 = {pid:#06x};\n\
+         pub const USB_MANUFACTURER: &str = {manufacturer:?};\n\
+         pub const USB_PRODUCT: &str = {product:?};\n\
+         pub const USB_ID_FEATURE: &core::ffi::CStr = c{id:?};\n\
+         pub const USB_MANUFACTURER_FEATURE: &core::ffi::CStr = c{manufacturer_feature:?};\n\
+         pub const USB_PRODUCT_FEATURE: &core::ffi::CStr = c{product_feature:?};\n",
+        id = format!("USB ID {vid:04X}:{pid:04X}"),
+        manufacturer_feature = format!("USB manufacturer {manufacturer}"),
+        product_feature = format!("USB product {product}"),
+    );
+    File::create(out.join("usb_identity.rs"))
+        .unwrap()
+        .write_all(code.as_bytes())
+        .unwrap();
+}
+
+/// Reads a VID or PID, either in hex with a 0x prefix or in decimal
+fn id_from_env(name: &str, default: u16) -> u16 {
+    println!("cargo:rerun-if-env-changed={name}");
+    let Ok(value) = env::var(name) else {
+        return default;
+    };
+    let value = value.trim();
+    let parsed = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.unwrap_or_else(|_| panic!("{name}={value} is not a 16 bit number"))
+}
+
+fn string_from_env(name: &str, default: &str) -> String {
+    println!("cargo:rerun-if-env-changed={name}");
+    let value = env::var(name).unwrap_or_else(|_| default.to_string());
+    if value.is_empty() || value.contains('\0') {
+        panic!("{name} can not be empty or contain a NUL character");
+    }
+    if value.encode_utf16().count() > MAX_STRING_LEN {
+        panic!("{name} is longer than the {MAX_STRING_LEN} characters USB allows");
+    }
+    value
+}
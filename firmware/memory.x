@@ -3,8 +3,19 @@ MEMORY {
      * The RP2350 has either external or internal flash.
      *
      * 2 MiB is a safe default here, although a Pico 2 has 4 MiB.
+     *
+     * The firmware runs from ACTIVE behind the embassy-boot bootloader in ../bootloader,
+     * whose memory.x has the same layout. Keep the two in sync.
+     *
+     * 0x10000000  36K    bootloader
+     * 0x10009000  4K     BOOTLOADER_STATE
+     * 0x1000A000  1000K  FLASH (the ACTIVE partition)
+     * 0x10104000  1004K  DFU, where updates are written to
+     * 0x101FF000  4K     device settings (see src/settings.rs)
      */
-    FLASH : ORIGIN = 0x10000000, LENGTH = 2048K
+    BOOTLOADER_STATE : ORIGIN = 0x10009000, LENGTH = 4K
+    FLASH : ORIGIN = 0x1000A000, LENGTH = 1000K
+    DFU : ORIGIN = 0x10104000, LENGTH = 1004K
     /*
      * RAM consists of 8 banks, SRAM0-SRAM7, with a striped mapping.
      * This is usually good for performance, as it distributes load on
@@ -21,6 +32,13 @@ MEMORY {
     SRAM5 : ORIGIN = 0x20081000, LENGTH = 4K
 }
 
+/* Offsets from the start of flash, used by embassy-boot */
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - 0x10000000;
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - 0x10000000;
+
+__bootloader_dfu_start = ORIGIN(DFU) - 0x10000000;
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - 0x10000000;
+
 SECTIONS {
     /* ### Boot ROM info
      *
@@ -1,29 +1,33 @@
 //! A basic postcard-rpc/poststation-compatible application
 
+use crate::display::{I2c1Bus, UiMutex};
 use crate::handlers::{
-    get_led, picoboot_reset, set_led, set_screen_text, sleep_handler, unique_id,
+    confirm_firmware, get_display_status, get_last_panic, get_led, get_log_level, get_name,
+    get_rgb_led, get_settings, ota_begin, ota_finish, ota_write, picoboot_reset, play_tone,
+    self_test, set_led, set_name, set_rgb_led, set_screen_text, set_settings, set_time,
+    show_metric, sleep_handler, unique_id,
 };
-use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
-use embassy_rp::i2c::{self, I2c};
-use embassy_rp::peripherals::I2C1;
-use embassy_rp::{gpio::Output, peripherals::USB, usb};
-use embassy_sync::blocking_mutex::raw::{NoopRawMutex, ThreadModeRawMutex};
+use crate::ota::Ota;
+use crate::settings::SettingsMutex;
+use crate::transport;
+use embassy_rp::{peripherals::USB, usb};
 use icd::{
-    GetLedEndpoint, GetUniqueIdEndpoint, RebootToPicoBoot, SetDisplayEndpoint, SetLedEndpoint,
-    SleepEndpoint,
+    ConfirmFirmwareEndpoint, GetDisplayStatusEndpoint, GetLastPanicEndpoint, GetLedEndpoint,
+    GetLogLevelEndpoint, GetNameEndpoint, GetRgbLedEndpoint, GetSettingsEndpoint,
+    GetUniqueIdEndpoint, LedState, NotifyEndpoint, OtaBeginEndpoint, OtaFinishEndpoint,
+    OtaWriteEndpoint, RebootToPicoBoot, RgbEffect, SelfTestEndpoint, SetDisplayEndpoint,
+    SetLedEndpoint, SetLogLevelEndpoint, SetNameEndpoint, SetRgbLedEndpoint, SetSettingsEndpoint,
+    SetTimeEndpoint, ShowMetricEndpoint, ShowQrCodeEndpoint, SleepEndpoint,
 };
-use icd::{ENDPOINT_LIST, TOPICS_IN_LIST, TOPICS_OUT_LIST};
+use icd::{PanicReport, ENDPOINT_LIST, TOPICS_IN_LIST, TOPICS_OUT_LIST};
 use postcard_rpc::server::impls::embassy_usb_v0_4::{
-    dispatch_impl::{spawn_fn, WireRxBuf, WireRxImpl, WireSpawnImpl, WireStorage, WireTxImpl},
+    dispatch_impl::{spawn_fn, WireRxBuf, WireSpawnImpl},
     PacketBuffers,
 };
 use postcard_rpc::{
     define_dispatch,
     server::{Server, SpawnContext},
 };
-use ssd1306::prelude::I2CInterface;
-use ssd1306::size::DisplaySize128x64;
-use ssd1306::Ssd1306Async;
 use static_cell::ConstStaticCell;
 
 /// Context contains the data that we will pass (as a mutable reference)
@@ -32,12 +36,21 @@ pub struct Context {
     /// We'll use this unique ID to identify ourselves to the poststation
     /// server. This should be unique per device.
     pub unique_id: u64,
-    pub led: Output<'static>,
-    pub display: Ssd1306Async<
-        I2CInterface<I2cDevice<'static, NoopRawMutex, I2c<'static, I2C1, i2c::Async>>>,
-        DisplaySize128x64,
-        ssd1306::mode::BufferedGraphicsModeAsync<DisplaySize128x64>,
-    >,
+    /// The pattern currently running on the onboard LED
+    pub led_state: LedState,
+    /// The effect currently shown on the WS2812 status LED
+    pub rgb_effect: RgbEffect,
+    /// Whether the last stats from the host were over an alert threshold
+    pub alerting: bool,
+    /// The panic that caused the last reset, if any
+    pub last_panic: Option<PanicReport>,
+    /// The display is shared with the encoder input task, so it lives behind a mutex
+    pub ui: &'static UiMutex,
+    pub settings: &'static SettingsMutex,
+    /// The display's I2C bus, for the self-test to scan
+    pub i2c_bus: &'static I2c1Bus,
+    /// Receives firmware updates into the DFU partition
+    pub ota: Ota,
 }
 
 impl SpawnContext for Context {
@@ -63,28 +76,19 @@ pub struct TaskContext {
 /// This alias describes the type of driver we will need. In this case, we
 /// are using the embassy-usb driver with the RP2040/2350 USB peripheral
 pub type AppDriver = usb::Driver<'static, USB>;
-/// Storage describes the things we need to keep as a static, so it can be shared
-/// with anyone who needs to send messages.
-///
-/// We can accept any mutex (this is using the thread-mode mutex, meaning that
-/// it will work outside of interrupts or interrupt executors). The numeric
-/// items control the buffer sizes allocated for Config, BOS, Control, and
-/// MSOS USB buffers. See embassy-usb for more details on this.
-pub type AppStorage = WireStorage<ThreadModeRawMutex, AppDriver, 256, 256, 64, 256>;
 /// BufStorage is the space used for receiving and sending frames. These values
 /// control the largest frames we can send or receive.
 pub type BufStorage = PacketBuffers<1024, 1024>;
-/// AppTx is the type of our sender, which is how we send information to the client
-pub type AppTx = WireTxImpl<ThreadModeRawMutex, AppDriver>;
+/// AppTx is the type of our sender, which is how we send information to the client.
+/// It answers over poststation's USB interface or the serial port, see `transport.rs`
+pub type AppTx = transport::AppTx;
 /// AppRx is the type of our receiver, which is how we receive information from the client
-pub type AppRx = WireRxImpl<AppDriver>;
+pub type AppRx = transport::AppRx;
 /// AppServer is the type of the postcard-rpc server we are using
 pub type AppServer = Server<AppTx, AppRx, WireRxBuf, MyApp>;
 
 /// Statically store our packet buffers
 pub static PBUFS: ConstStaticCell<BufStorage> = ConstStaticCell::new(BufStorage::new());
-/// Statically store our USB app buffers
-pub static STORAGE: AppStorage = AppStorage::new();
 
 // This macro defines your application
 define_dispatch! {
@@ -127,6 +131,26 @@ define_dispatch! {
         | SetLedEndpoint            | blocking  | set_led                       |
         | GetLedEndpoint            | blocking  | get_led                       |
         | SetDisplayEndpoint        | async     | set_screen_text               |
+        | GetSettingsEndpoint       | async     | get_settings                  |
+        | SetSettingsEndpoint       | async     | set_settings                  |
+        | SetRgbLedEndpoint         | blocking  | set_rgb_led                   |
+        | GetRgbLedEndpoint         | blocking  | get_rgb_led                   |
+        | PlayToneEndpoint          | blocking  | play_tone                     |
+        | GetLastPanicEndpoint      | blocking  | get_last_panic                |
+        | SetLogLevelEndpoint       | blocking  | set_log_level                 |
+        | GetLogLevelEndpoint       | blocking  | get_log_level                 |
+        | OtaBeginEndpoint          | blocking  | ota_begin                     |
+        | OtaWriteEndpoint          | blocking  | ota_write                     |
+        | OtaFinishEndpoint         | blocking  | ota_finish                    |
+        | ConfirmFirmwareEndpoint   | blocking  | confirm_firmware              |
+        | SelfTestEndpoint          | async     | self_test                     |
+        | GetDisplayStatusEndpoint  | async     | get_display_status            |
+        | SetNameEndpoint           | async     | set_name                      |
+        | GetNameEndpoint           | async     | get_name                      |
+        | SetTimeEndpoint           | blocking  | set_time                      |
+        | NotifyEndpoint            | async     | notify                        |
+        | ShowMetricEndpoint        | async     | show_metric                   |
+        | ShowQrCodeEndpoint        | async     | show_qr_code                  |
     };
 
     // Topics IN are messages we receive from the client, but that we do not reply
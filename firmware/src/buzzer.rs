@@ -0,0 +1,88 @@
+//! A passive piezo buzzer on a PWM pin, for tones from the host and the alert alarm
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_rp::{
+    clocks::clk_sys_freq,
+    pwm::{Config, Pwm},
+};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_time::Timer;
+use icd::PlayTone;
+
+/// Slows the PWM counter down so audible frequencies fit in the 16 bit `top`
+const PWM_DIVIDER: u8 = 64;
+/// Played when a metric goes over its alert threshold
+const ALARM: PlayTone = PlayTone {
+    frequency_hz: 2000,
+    duration_ms: 100,
+    pause_ms: 100,
+    repeat: 3,
+};
+
+static TONES: Channel<ThreadModeRawMutex, PlayTone, 4> = Channel::new();
+static MUTED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_muted(muted: bool) {
+    MUTED.store(muted, Ordering::Relaxed);
+}
+
+/// Queues a tone, returns false if muted or the queue is full
+pub fn play(tone: PlayTone) -> bool {
+    !MUTED.load(Ordering::Relaxed) && TONES.try_send(tone).is_ok()
+}
+
+/// Sounds the alert alarm
+pub fn alarm() {
+    let _ = play(ALARM);
+}
+
+pub struct Buzzer {
+    pwm: Pwm<'static>,
+    config: Config,
+}
+
+impl Buzzer {
+    /// Takes a PWM with the buzzer on its B channel
+    pub fn new(mut pwm: Pwm<'static>) -> Self {
+        let mut config = Config::default();
+        config.divider = PWM_DIVIDER.into();
+        config.compare_b = 0;
+        pwm.set_config(&config);
+        Buzzer { pwm, config }
+    }
+
+    /// Starts a square wave at `frequency_hz`, 0 is silence
+    fn start(&mut self, frequency_hz: u16) {
+        if frequency_hz == 0 {
+            return self.stop();
+        }
+        let counter_hz = clk_sys_freq() / PWM_DIVIDER as u32;
+        let top = (counter_hz / frequency_hz as u32).clamp(2, u16::MAX as u32) as u16;
+        self.config.top = top;
+        self.config.compare_b = top / 2;
+        self.pwm.set_config(&self.config);
+    }
+
+    fn stop(&mut self) {
+        self.config.compare_b = 0;
+        self.pwm.set_config(&self.config);
+    }
+}
+
+/// Plays queued tones one after the other
+#[embassy_executor::task]
+pub async fn buzzer_task(mut buzzer: Buzzer) {
+    loop {
+        let tone = TONES.receive().await;
+        for _ in 0..tone.repeat.max(1) {
+            //Muting also cuts off anything that is already playing
+            if MUTED.load(Ordering::Relaxed) {
+                break;
+            }
+            buzzer.start(tone.frequency_hz);
+            Timer::after_millis(tone.duration_ms.into()).await;
+            buzzer.stop();
+            Timer::after_millis(tone.pause_ms.into()).await;
+        }
+    }
+}
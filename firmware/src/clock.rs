@@ -0,0 +1,95 @@
+//! Wall-clock time from the host, kept going with the device's own timer between syncs.
+//!
+//! The host sends a [`TimeSync`] when it connects and every few minutes after that. The time in
+//! between is the last sync plus the [`Instant`] elapsed since it came in, so there is no RTC to
+//! set and a missed sync only costs a little drift.
+
+use crate::display::{SharedRawMutex, UiMutex};
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Ticker};
+use icd::TimeSync;
+
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// The last sync and when it arrived. Read while drawing, which may happen on the other core
+static LAST_SYNC: Mutex<SharedRawMutex, Cell<Option<(TimeSync, Instant)>>> =
+    Mutex::new(Cell::new(None));
+
+pub fn sync(time: TimeSync) {
+    LAST_SYNC.lock(|last| last.set(Some((time, Instant::now()))));
+}
+
+/// The current local time, if the host has sent it since boot
+pub fn now() -> Option<LocalTime> {
+    let (time, synced_at) = LAST_SYNC.lock(|last| last.get())?;
+    let seconds = time.unix_seconds
+        + time.utc_offset_minutes as i64 * 60
+        + synced_at.elapsed().as_secs() as i64;
+    Some(LocalTime::from_seconds(seconds))
+}
+
+/// A calendar date and time of day, without a time zone
+#[derive(Clone, Copy, PartialEq)]
+pub struct LocalTime {
+    pub year: i32,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    /// 0 is Monday
+    pub weekday: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl LocalTime {
+    /// Splits seconds since 1970-01-01 00:00 into a date and time, see
+    /// <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+    fn from_seconds(seconds: i64) -> Self {
+        let days = seconds.div_euclid(86_400);
+        let second_of_day = seconds.rem_euclid(86_400);
+
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+        let month = if month_from_march < 10 {
+            month_from_march + 3
+        } else {
+            month_from_march - 9
+        };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        LocalTime {
+            year: year as i32,
+            month: month as u8,
+            day: day as u8,
+            // 1970-01-01 was a Thursday
+            weekday: (days + 3).rem_euclid(7) as u8,
+            hour: (second_of_day / 3600) as u8,
+            minute: (second_of_day / 60 % 60) as u8,
+            second: (second_of_day % 60) as u8,
+        }
+    }
+
+    pub fn weekday_name(&self) -> &'static str {
+        WEEKDAYS[self.weekday as usize]
+    }
+}
+
+/// Ticks the display once a second so the clock keeps going without the host sending anything,
+/// see [`crate::display::Ui::tick`]
+#[embassy_executor::task]
+pub async fn clock_task(ui: &'static UiMutex) {
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+    loop {
+        ticker.next().await;
+        ui.lock().await.tick();
+    }
+}
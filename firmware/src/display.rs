@@ -0,0 +1,1471 @@
+//! Everything that ends up on the display, the stats pages and the settings menu.
+//!
+//! The rest of the firmware only changes the [`Ui`], which asks for a new frame. [`render_task`]
+//! is the only one talking to the panel, so a slow transfer never holds up USB or the encoder.
+//!
+//! Pages are drawn in a 128x64 layout on any [`Canvas`], in the colors of a [`Palette`]. The
+//! SSD1306 in [`crate::oled`] is the default panel, the `spi-display` features swap in a color
+//! panel from [`crate::tft`]. The e-paper panel in [`crate::epaper`] only refreshes about once a
+//! minute, so it gets layouts of its own with large numbers and graphs of the last hour.
+//!
+//! With the `core1-render` feature the render task runs on the second core with an executor of
+//! its own, see [`spawn_on_core1`]. Everything it shares with core 0 is then behind a
+//! [`SharedRawMutex`].
+//!
+//! A display that stops responding (a loose cable, a module plugged back in) is marked offline
+//! instead of taking anything else down, and the render task keeps re-initializing it with a
+//! growing delay until it answers again.
+
+use crate::{
+    buzzer,
+    clock::{self, LocalTime},
+    io::{TextWriter, ELLIPSIS},
+    logging,
+    menu::Menu,
+    notify::NotificationQueue,
+    qr::{self, QrImage},
+    theme::{Canvas, Palette, UiColor},
+};
+use core::fmt::Write;
+use core::ops::Range;
+#[cfg(feature = "core1-render")]
+use embassy_executor::Executor;
+use embassy_futures::select::{select, Either};
+use embassy_rp::i2c::{self, I2c};
+use embassy_rp::peripherals::I2C1;
+#[cfg(feature = "core1-render")]
+use embassy_rp::{
+    multicore::{spawn_core1, Stack},
+    peripherals::CORE1,
+};
+#[cfg(feature = "core1-render")]
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+#[cfg(not(feature = "core1-render"))]
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_graphics::{
+    mono_font::{ascii, MonoFont},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Arc, Circle, Line, PrimitiveStyle, Rectangle, Triangle},
+    text::{Alignment, Baseline, Text},
+};
+use heapless::{Deque, String, Vec};
+use icd::{
+    DeviceSettings, DisplayStatus, Icon, Layout, Metric, MetricChoice, MultiHostLayout,
+    Notification, Page, Priority, QrCode, SysInfo, QR_CAPTION_LEN,
+};
+#[cfg(feature = "core1-render")]
+use static_cell::{ConstStaticCell, StaticCell};
+use tinybmp::Bmp;
+
+/// Guards what the render task shares with the rest of the firmware. On core 1 that takes a lock
+/// that works across cores
+#[cfg(feature = "core1-render")]
+pub type SharedRawMutex = CriticalSectionRawMutex;
+#[cfg(not(feature = "core1-render"))]
+pub type SharedRawMutex = ThreadModeRawMutex;
+
+pub type I2c1Bus = Mutex<SharedRawMutex, I2c<'static, I2C1, i2c::Async>>;
+/// Shared by [`render_task`], the handlers and the input, clock and notification tasks. Nobody
+/// holds it across an await, bind what is needed first and let the guard go before waiting
+pub type UiMutex = Mutex<SharedRawMutex, Ui>;
+
+/// First wait before re-initializing a display that stopped responding, doubled on every failure
+const RETRY_MIN: Duration = Duration::from_millis(250);
+const RETRY_MAX: Duration = Duration::from_secs(8);
+/// The host sends stats twice a second, after this long without any the clock takes over
+const HOST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Shortest time between two frames, changes coming in faster than this are drawn together
+const FRAME_TIME: Duration = Duration::from_millis(50);
+/// Most hosts whose stats are kept at once, four columns still fit side by side
+const MAX_HOSTS: usize = 4;
+/// How long each host stays on screen when rotating between them
+const HOST_ROTATE: Duration = Duration::from_secs(5);
+/// The graphs on e-paper show the last hour, averaged over half a minute per point
+const HISTORY_LEN: usize = 120;
+const HISTORY_STEP: Duration = Duration::from_secs(30);
+/// How long the peak marker on the gauges stays put before following the usage back down
+const PEAK_HOLD: Duration = Duration::from_secs(10);
+/// How long the self-test checkerboard stays up
+const TEST_PATTERN_TIME: Duration = Duration::from_secs(3);
+/// The order the knob steps through the metrics in large digits
+const METRICS: [Metric; 2] = [Metric::Cpu, Metric::Memory];
+/// Segments lit for each digit in large digits. Bit 0 is the top segment, the next ones go
+/// clockwise around the digit and bit 6 is the middle one
+const SEGMENTS: [u8; 10] = [0x3F, 0x06, 0x5B, 0x4F, 0x66, 0x6D, 0x7D, 0x07, 0x7F, 0x6F];
+/// Draws the layouts meant for e-paper instead of the pages
+const SLOW_REFRESH: bool = cfg!(feature = "epaper");
+
+const TEXT_FONT: &MonoFont<'static> = &ascii::FONT_8X13;
+pub const SMALL_FONT: &MonoFont<'static> = &ascii::FONT_6X10;
+const CLOCK_FONT: &MonoFont<'static> = &ascii::FONT_10X20;
+
+/// An owned copy of the last [`SysInfo`] from the host, so pages can be redrawn without waiting on it
+pub struct Stats {
+    pub host_name: String<32>,
+    pub cpu_freq_text: String<16>,
+    pub cpu_usage: u8,
+    pub memory_usage: u64,
+    pub total_memory: u64,
+    pub scroll_text: String<32>,
+}
+
+impl Stats {
+    pub fn from_sys_info(info: &SysInfo<'_>) -> Self {
+        Stats {
+            host_name: truncated(info.host_name),
+            cpu_freq_text: truncated(info.cpu_freq_text),
+            cpu_usage: info.cpu_usage,
+            memory_usage: info.memory_usage,
+            total_memory: info.total_memory,
+            scroll_text: truncated(info.scroll_text),
+        }
+    }
+
+    pub fn memory_percent(&self) -> u8 {
+        match self.total_memory {
+            0 => 0,
+            total => (self.memory_usage.min(total) * 100 / total) as u8,
+        }
+    }
+
+    /// True if either usage is at or over its configured alert threshold
+    pub fn is_alerting(&self, settings: &DeviceSettings) -> bool {
+        let over = |value: u8, threshold: u8| threshold != 0 && value >= threshold;
+        over(self.cpu_usage, settings.cpu_alert)
+            || over(self.memory_percent(), settings.memory_alert)
+    }
+}
+
+/// The last stats from one host
+struct Host {
+    /// [`SysInfo::source`]
+    source: u8,
+    stats: Stats,
+    /// When they came in
+    at: Instant,
+    history: History,
+    cpu_peak: Peak,
+    memory_peak: Peak,
+}
+
+impl Host {
+    fn is_quiet(&self) -> bool {
+        self.at.elapsed() > HOST_TIMEOUT
+    }
+}
+
+/// The highest usage lately, for the peak marker on the gauges
+struct Peak {
+    percent: u8,
+    /// When it was reached
+    at: Instant,
+}
+
+impl Peak {
+    fn new(percent: u8) -> Self {
+        Peak {
+            percent,
+            at: Instant::now(),
+        }
+    }
+
+    /// Moves up right away, and down once the peak is older than [`PEAK_HOLD`]
+    fn update(&mut self, percent: u8) {
+        if percent >= self.percent || self.at.elapsed() >= PEAK_HOLD {
+            *self = Peak::new(percent);
+        }
+    }
+}
+
+/// Usage averaged over every [`HISTORY_STEP`], oldest first
+struct History {
+    cpu: Deque<u8, HISTORY_LEN>,
+    memory: Deque<u8, HISTORY_LEN>,
+    /// Stats added since `since`, averaged into the next point
+    cpu_sum: u32,
+    memory_sum: u32,
+    samples: u32,
+    since: Instant,
+}
+
+impl History {
+    fn new() -> Self {
+        History {
+            cpu: Deque::new(),
+            memory: Deque::new(),
+            cpu_sum: 0,
+            memory_sum: 0,
+            samples: 0,
+            since: Instant::now(),
+        }
+    }
+
+    fn add(&mut self, stats: &Stats) {
+        self.cpu_sum += stats.cpu_usage as u32;
+        self.memory_sum += stats.memory_percent() as u32;
+        self.samples += 1;
+        if self.since.elapsed() < HISTORY_STEP {
+            return;
+        }
+        let points = [
+            (&mut self.cpu, self.cpu_sum),
+            (&mut self.memory, self.memory_sum),
+        ];
+        for (values, sum) in points {
+            if values.is_full() {
+                values.pop_front();
+            }
+            let _ = values.push_back((sum / self.samples) as u8);
+        }
+        self.cpu_sum = 0;
+        self.memory_sum = 0;
+        self.samples = 0;
+        self.since = Instant::now();
+    }
+}
+
+/// Copies as much of `text` as fits, without splitting a character
+fn truncated<const N: usize>(text: &str) -> String<N> {
+    let mut out = String::new();
+    for c in text.chars() {
+        if out.push(c).is_err() {
+            break;
+        }
+    }
+    out
+}
+
+/// Addresses on the bus that acknowledge a one byte read, skipping the reserved ones
+pub async fn scan_i2c(bus: &I2c1Bus) -> Vec<u8, 16> {
+    let mut found = Vec::new();
+    for address in 0x08..0x78u8 {
+        let mut byte = [0u8; 1];
+        let result = bus.lock().await.read_async(address, &mut byte).await;
+        if result.is_ok() && found.push(address).is_err() {
+            break;
+        }
+    }
+    found
+}
+
+/// Raised whenever something on screen should change, wakes up [`render_task`]
+static RENDER: Signal<SharedRawMutex, ()> = Signal::new();
+/// Whether the frame with the test pattern made it to the panel, for [`Ui::show_test_pattern`]
+static TEST_PATTERN_SENT: Signal<SharedRawMutex, bool> = Signal::new();
+
+/// Asks [`render_task`] for a new frame. Requests that come in faster than the frame rate end up
+/// in the same frame
+pub fn request_render() {
+    RENDER.signal(());
+}
+
+#[cfg(feature = "epaper")]
+pub use crate::epaper::Panel;
+#[cfg(not(any(feature = "spi-display", feature = "epaper")))]
+pub use crate::oled::Panel;
+#[cfg(feature = "spi-display")]
+pub use crate::tft::Panel;
+
+/// The columns from the first to the last one that differ, if any do
+pub fn changed_columns<T: PartialEq>(new: &[T], old: &[T]) -> Option<Range<usize>> {
+    let first = new.iter().zip(old).position(|(n, o)| n != o)?;
+    let last = new.iter().zip(old).rposition(|(n, o)| n != o)?;
+    Some(first..last + 1)
+}
+
+/// Draws the [`Ui`] into the panel's frame and brings the panel in line with it. The ui is only
+/// locked while drawing, not while sending
+async fn render(panel: &mut Panel, ui: &UiMutex, status: &mut DisplayStatus) -> Result<(), ()> {
+    let (asleep, settings, test_pattern) = {
+        let ui = ui.lock().await;
+        ui.draw(panel.frame());
+        (ui.asleep, ui.settings.clone(), ui.test_pattern.is_some())
+    };
+
+    if asleep {
+        //The frame is sent once the panel is back on
+        return panel.set_on(false).await;
+    }
+    panel.set_on(true).await?;
+    panel.configure(&settings).await?;
+
+    let started = Instant::now();
+    let sent = panel.flush().await;
+    if let Ok(bytes @ 1..) = sent {
+        let took = started.elapsed().as_micros() as u32;
+        status.last_flush_bytes = bytes as u32;
+        status.last_flush_us = took;
+        status.slowest_flush_us = status.slowest_flush_us.max(took);
+    }
+    if test_pattern {
+        TEST_PATTERN_SENT.signal(sent.is_ok());
+    }
+    sent.map(drop)
+}
+
+/// What should be on the display. Changing it only requests a new frame from [`render_task`],
+/// so handlers holding the lock never wait on the panel
+pub struct Ui {
+    /// As last reported by [`render_task`]
+    status: DisplayStatus,
+    /// The panel is switched off while USB is suspended, frames are still drawn but not sent
+    asleep: bool,
+    settings: DeviceSettings,
+    /// Every host sending stats, by source. One that goes quiet is dropped while others are still
+    /// sending, the last one is kept
+    hosts: Vec<Host, MAX_HOSTS>,
+    /// Index into `hosts` of the one on screen when rotating
+    host: usize,
+    /// When `host` last changed
+    host_since: Instant,
+    /// Index into [`Ui::pages`]
+    page: usize,
+    /// Shown in [`Layout::LargeDigits`]
+    metric: Metric,
+    pub menu: Option<Menu>,
+    notifications: NotificationQueue,
+    /// The notification on screen and when it comes down
+    notification: Option<(Notification, Instant)>,
+    /// The QR code on screen and its caption
+    qr_code: Option<(QrImage, String<QR_CAPTION_LEN>)>,
+    /// Set when the host went away, until it sends stats again
+    disconnected: bool,
+    /// Set by the self-test, the checkerboard replaces everything else until then
+    test_pattern: Option<Instant>,
+}
+
+impl Ui {
+    pub fn new(settings: DeviceSettings) -> Self {
+        buzzer::set_muted(settings.buzzer_muted);
+        Ui {
+            status: DisplayStatus {
+                ready: false,
+                failures: 0,
+                recoveries: 0,
+                last_flush_bytes: 0,
+                last_flush_us: 0,
+                slowest_flush_us: 0,
+            },
+            asleep: false,
+            settings,
+            hosts: Vec::new(),
+            host: 0,
+            host_since: Instant::from_ticks(0),
+            page: 0,
+            metric: Metric::Cpu,
+            menu: None,
+            notifications: NotificationQueue::new(),
+            notification: None,
+            qr_code: None,
+            disconnected: false,
+            test_pattern: None,
+        }
+    }
+
+    pub fn status(&self) -> DisplayStatus {
+        self.status
+    }
+
+    /// Switches the panel off until [`Ui::wake`], keeping what should be on it
+    pub fn sleep(&mut self) {
+        self.asleep = true;
+        request_render();
+    }
+
+    /// Switches the panel back on with the layout from before [`Ui::sleep`]
+    pub fn wake(&mut self) {
+        self.asleep = false;
+        request_render();
+    }
+
+    pub fn settings(&self) -> &DeviceSettings {
+        &self.settings
+    }
+
+    /// Redraws with the new settings, the panel picks up brightness and rotation on the next frame.
+    /// The buzzer mute is applied here too, since every settings change passes through
+    pub fn apply_settings(&mut self, settings: DeviceSettings) {
+        buzzer::set_muted(settings.buzzer_muted);
+        //The host changed them while the menu was open
+        if let Some(menu) = self.menu.as_mut() {
+            menu.refresh(&settings);
+        }
+        self.settings = settings;
+        request_render();
+    }
+
+    pub fn show_stats(&mut self, source: u8, stats: Stats) {
+        let at = Instant::now();
+        self.disconnected = false;
+        match self.hosts.iter_mut().find(|host| host.source == source) {
+            Some(host) => {
+                host.history.add(&stats);
+                host.cpu_peak.update(stats.cpu_usage);
+                host.memory_peak.update(stats.memory_percent());
+                host.stats = stats;
+                host.at = at;
+            }
+            None => {
+                self.forget_quiet_hosts();
+                let mut history = History::new();
+                history.add(&stats);
+                let host = Host {
+                    source,
+                    cpu_peak: Peak::new(stats.cpu_usage),
+                    memory_peak: Peak::new(stats.memory_percent()),
+                    stats,
+                    at,
+                    history,
+                };
+                if self.hosts.push(host).is_err() {
+                    logging::debug!("Already showing {} hosts, ignoring {}", MAX_HOSTS, source);
+                    return;
+                }
+                self.hosts.sort_unstable_by_key(|host| host.source);
+            }
+        }
+        request_render();
+    }
+
+    /// Called when the connection to the host is lost
+    pub fn set_disconnected(&mut self) {
+        self.disconnected = true;
+        request_render();
+    }
+
+    /// Drops the hosts that stopped sending, unless none are left sending. Returns true if any were
+    fn forget_quiet_hosts(&mut self) -> bool {
+        let before = self.hosts.len();
+        if self.hosts.iter().any(|host| !host.is_quiet()) {
+            self.hosts.retain(|host| !host.is_quiet());
+        }
+        self.hosts.len() != before
+    }
+
+    /// True if any host is over one of the alert thresholds
+    pub fn is_alerting(&self) -> bool {
+        self.hosts
+            .iter()
+            .any(|host| host.stats.is_alerting(&self.settings))
+    }
+
+    /// The highest CPU usage of all hosts
+    pub fn busiest_cpu(&self) -> u8 {
+        self.hosts
+            .iter()
+            .map(|host| host.stats.cpu_usage)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The configured page order, followed by the clock if it is on and the time is known
+    fn pages(&self) -> Vec<Page, 4> {
+        let mut pages = Vec::new();
+        let _ = pages.extend_from_slice(&self.settings.page_order);
+        if self.settings.show_clock && clock::now().is_some() {
+            let _ = pages.push(Page::Clock);
+        }
+        pages
+    }
+
+    fn current_page(&self) -> Page {
+        let pages = self.pages();
+        pages.get(self.page).copied().unwrap_or(pages[0])
+    }
+
+    /// Moves `steps` pages forward (or back if negative) in the configured page order, or
+    /// through the metrics when showing large digits
+    pub fn change_page(&mut self, steps: i8) {
+        if self.settings.layout == Layout::LargeDigits {
+            self.step_metric(steps);
+        } else {
+            let pages = self.pages().len() as i8;
+            self.page = (self.page as i8 + steps).rem_euclid(pages) as usize;
+        }
+        request_render();
+    }
+
+    /// Shows `metric` in large digits, or the next one for `None`. Returns the one now shown
+    pub fn show_metric(&mut self, metric: MetricChoice) -> Metric {
+        match metric {
+            Some(metric) => self.metric = metric,
+            None => self.step_metric(1),
+        }
+        request_render();
+        self.metric
+    }
+
+    fn step_metric(&mut self, steps: i8) {
+        let current = METRICS.iter().position(|m| *m == self.metric).unwrap_or(0) as i8;
+        self.metric = METRICS[(current + steps).rem_euclid(METRICS.len() as i8) as usize];
+    }
+
+    /// The host whose stats are on screen, unless they are side by side
+    fn shown_host(&self) -> Option<&Host> {
+        self.hosts.get(self.host).or(self.hosts.first())
+    }
+
+    /// The time to show instead of the stats, when the clock page is up or the host went quiet
+    fn clock_to_show(&self) -> Option<LocalTime> {
+        let time = clock::now()?;
+        let host_gone = self.hosts.iter().all(Host::is_quiet);
+        (host_gone || self.current_page() == Page::Clock).then_some(time)
+    }
+
+    /// Queues a notification from the host, see [`NotificationQueue::push`]
+    pub fn queue_notification(&mut self, notification: Notification) -> bool {
+        self.notifications.push(notification)
+    }
+
+    /// Puts the next queued notification on screen unless one is already up.
+    /// Returns when the one on screen should come down
+    pub fn show_next_notification(&mut self) -> Option<Instant> {
+        if self.notification.is_none() {
+            let next = self.notifications.pop()?;
+            let until = Instant::now() + Duration::from_millis(next.duration_ms.into());
+            self.notification = Some((next, until));
+            request_render();
+        }
+        self.notification.as_ref().map(|(_, until)| *until)
+    }
+
+    pub fn end_notification(&mut self) {
+        self.notification = None;
+        request_render();
+    }
+
+    /// Puts a QR code on screen for its `duration_ms`, replacing the one up already.
+    /// Returns false if the text is too long to encode
+    pub fn show_qr_code(&mut self, code: QrCode) -> bool {
+        let Some(image) = QrImage::encode(&code.text) else {
+            return false;
+        };
+        self.qr_code = Some((image, code.caption));
+        qr::show_until(Instant::now() + Duration::from_millis(code.duration_ms.into()));
+        request_render();
+        true
+    }
+
+    pub fn end_qr_code(&mut self) {
+        self.qr_code = None;
+        request_render();
+    }
+
+    /// Called every second, keeps the clock ticking when it is on screen and moves on to the
+    /// next host when rotating
+    pub fn tick(&mut self) {
+        let mut changed = self.forget_quiet_hosts();
+        let rotating = self.settings.multi_host == MultiHostLayout::Rotate && self.hosts.len() > 1;
+        if rotating && self.host_since.elapsed() >= HOST_ROTATE {
+            self.host = (self.host + 1) % self.hosts.len();
+            self.host_since = Instant::now();
+            changed = true;
+        }
+        if self.menu.is_none() && (changed || self.clock_to_show().is_some()) {
+            request_render();
+        }
+    }
+
+    /// Draws the menu if it is open, then a QR code, otherwise the current page. The clock replaces
+    /// the stats when the host is gone, notifications go on top of either or take over the screen.
+    /// The boot screen stays up until there is something else to show
+    fn draw<D: Canvas>(&self, display: &mut D) {
+        let palette = D::Color::palette(&self.settings.theme);
+        let _ = display.clear(palette.background);
+        if self.test_pattern.is_some() {
+            draw_test_pattern(display, &palette);
+            return;
+        }
+        if let Some(menu) = &self.menu {
+            menu.draw(display, &palette);
+            return;
+        }
+        if self.disconnected {
+            draw_disconnected(display, &palette);
+            return;
+        }
+        if let Some((image, caption)) = &self.qr_code {
+            qr::draw(display, &palette, image, caption);
+            return;
+        }
+        let notification = self.notification.as_ref().map(|(n, _)| n);
+        if let Some(full) = notification.filter(|n| n.priority == Priority::High) {
+            draw_full_notification(display, &palette, full);
+            return;
+        }
+
+        if SLOW_REFRESH {
+            self.draw_for_slow_refresh(display, &palette);
+        } else {
+            self.draw_pages(display, &palette);
+        }
+        if let Some(banner) = notification {
+            draw_banner(display, &palette, banner);
+        }
+    }
+
+    /// The current page, or the clock when it replaces them
+    fn draw_pages<D: Canvas>(&self, display: &mut D, palette: &Palette<D::Color>) {
+        let page = self.current_page();
+        if let Some(time) = self.clock_to_show() {
+            draw_clock(display, palette, &time);
+        } else if let (Layout::LargeDigits, Some(host)) = (self.settings.layout, self.shown_host())
+        {
+            draw_large_digits(display, palette, host, self.metric, false);
+            if host.stats.is_alerting(&self.settings) {
+                draw_alert(display, palette);
+            }
+        } else if let (Layout::Gauges, Some(host)) = (self.settings.layout, self.shown_host()) {
+            draw_gauges(display, palette, host, self.settings.peak_hold, false);
+            if host.stats.is_alerting(&self.settings) {
+                draw_alert(display, palette);
+            }
+        } else if self.settings.multi_host == MultiHostLayout::SideBySide && self.hosts.len() > 1 {
+            draw_side_by_side(display, palette, &self.hosts, &self.settings);
+        } else if let Some(host) = self.shown_host() {
+            let stats = &host.stats;
+            match page {
+                Page::Overview => draw_overview(display, palette, stats),
+                Page::Cpu => draw_cpu(display, palette, stats),
+                Page::Memory => draw_memory(display, palette, stats),
+                //Only reached when the host put the clock in page_order before sending the time
+                Page::Clock => draw_waiting_for_time(display, palette),
+            }
+            //The overview already starts with the host name
+            if self.hosts.len() > 1 && page != Page::Overview {
+                draw_host_name(display, palette, &stats.host_name);
+            }
+            if stats.is_alerting(&self.settings) {
+                draw_alert(display, palette);
+            }
+        } else {
+            draw_boot_screen(display, palette, &self.settings.name);
+        }
+    }
+
+    /// Layouts for e-paper, which stay up for a minute or more: the time without seconds, and
+    /// usage as averages and graphs rather than whatever it was at the moment of the refresh
+    fn draw_for_slow_refresh<D: Canvas>(&self, display: &mut D, palette: &Palette<D::Color>) {
+        if let Some(time) = self.clock_to_show() {
+            draw_large_clock(display, palette, &time);
+        } else if let Some(host) = self.shown_host() {
+            match self.settings.layout {
+                Layout::Pages => draw_history(display, palette, host),
+                Layout::LargeDigits => draw_large_digits(display, palette, host, self.metric, true),
+                Layout::Gauges => {
+                    draw_gauges(display, palette, host, self.settings.peak_hold, true)
+                }
+            }
+            if host.stats.is_alerting(&self.settings) {
+                draw_alert(display, palette);
+            }
+        } else {
+            draw_boot_screen(display, palette, &self.settings.name);
+        }
+    }
+
+    /// Whether someone is using the device right now, so slow panels should keep up
+    fn is_interactive(&self) -> bool {
+        self.menu.is_some() || self.test_pattern.is_some()
+    }
+}
+
+/// Shows a checkerboard for [`TEST_PATTERN_TIME`], [`render_task`] takes it down again.
+/// Returns whether its first frame made it to the panel
+pub async fn show_test_pattern(ui: &UiMutex) -> bool {
+    TEST_PATTERN_SENT.reset();
+    ui.lock().await.test_pattern = Some(Instant::now() + TEST_PATTERN_TIME);
+    request_render();
+    //A display that is offline is only retried every few seconds, no point in waiting on it
+    with_timeout(Duration::from_secs(1), TEST_PATTERN_SENT.wait())
+        .await
+        .unwrap_or(false)
+}
+
+/// Starts core 1 with its own executor running [`render_task`], so drawing never competes with
+/// USB on core 0. The I2C interrupt stays on core 0, its waker reaches the other executor all the same
+#[cfg(feature = "core1-render")]
+pub fn spawn_on_core1(core1: CORE1, ui: &'static UiMutex, panel: Panel, ready: bool) {
+    static STACK: ConstStaticCell<Stack<8192>> = ConstStaticCell::new(Stack::new());
+    static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+    spawn_core1(core1, STACK.take(), move || {
+        let executor = EXECUTOR.init(Executor::new());
+        executor.run(|spawner| spawner.must_spawn(render_task(ui, panel, ready)))
+    });
+}
+
+/// Draws a new frame whenever the [`Ui`] changes, no more often than every [`FRAME_TIME`].
+/// A display that stops responding is re-initialized with a growing delay until it answers again.
+/// `ready` is whether the panel's first init worked
+#[embassy_executor::task]
+pub async fn render_task(ui: &'static UiMutex, mut panel: Panel, ready: bool) {
+    let mut status = DisplayStatus {
+        ready,
+        failures: !ready as u32,
+        recoveries: 0,
+        last_flush_bytes: 0,
+        last_flush_us: 0,
+        slowest_flush_us: 0,
+    };
+    let mut retry = RETRY_MIN;
+    //The boot screen, or whatever the ui got to while the panel was set up
+    request_render();
+    loop {
+        if status.ready {
+            let test_pattern = ui.lock().await.test_pattern;
+            match test_pattern {
+                Some(until) => {
+                    if let Either::First(()) = select(Timer::at(until), RENDER.wait()).await {
+                        ui.lock().await.test_pattern = None;
+                    }
+                }
+                None => RENDER.wait().await,
+            }
+        } else {
+            Timer::after(retry).await;
+            retry = (retry * 2).min(RETRY_MAX);
+            if !panel.init().await {
+                continue;
+            }
+            retry = RETRY_MIN;
+            status.ready = true;
+            status.recoveries += 1;
+            logging::info!("Display is back after {} failures", status.failures);
+        }
+
+        //Panels with slow refreshes hold changes back until the next refresh is due, unless
+        //the menu is open. Whatever changed meanwhile goes out together
+        while let Some(due) = panel.next_refresh() {
+            if ui.lock().await.is_interactive() {
+                break;
+            }
+            if let Either::First(()) = select(Timer::at(due), RENDER.wait()).await {
+                break;
+            }
+        }
+
+        let started = Instant::now();
+        if render(&mut panel, ui, &mut status).await.is_err() {
+            status.ready = false;
+            status.failures += 1;
+            logging::warn!(
+                "Display is not responding ({} times so far)",
+                status.failures
+            );
+        }
+        ui.lock().await.status = status;
+        Timer::at(started + FRAME_TIME).await;
+    }
+}
+
+fn draw_overview<D: Canvas>(display: &mut D, palette: &Palette<D::Color>, stats: &Stats) {
+    let buffer = &mut [0u8; 1024];
+    let mut writer = TextWriter::new(buffer);
+
+    let _ = write!(
+        &mut writer,
+        "{}\nCPU:{} {}% \nRam:{}/{}\n\n{}",
+        stats.host_name,
+        stats.cpu_freq_text,
+        stats.cpu_usage,
+        stats.memory_usage,
+        stats.total_memory,
+        stats.scroll_text
+    );
+    let _ = Text::with_baseline(
+        writer.as_str(),
+        Point::zero(),
+        palette.text(TEXT_FONT),
+        Baseline::Top,
+    )
+    .draw(display);
+}
+
+fn draw_cpu<D: Canvas>(display: &mut D, palette: &Palette<D::Color>, stats: &Stats) {
+    let buffer = &mut [0u8; 64];
+    let mut writer = TextWriter::new(buffer);
+    let _ = write!(
+        &mut writer,
+        "CPU {}%\n{}",
+        stats.cpu_usage, stats.cpu_freq_text
+    );
+    let _ = Text::with_baseline(
+        writer.as_str(),
+        Point::zero(),
+        palette.text(TEXT_FONT),
+        Baseline::Top,
+    )
+    .draw(display);
+    draw_bar(display, palette, stats.cpu_usage);
+}
+
+fn draw_memory<D: Canvas>(display: &mut D, palette: &Palette<D::Color>, stats: &Stats) {
+    let buffer = &mut [0u8; 64];
+    let mut writer = TextWriter::new(buffer);
+    let _ = write!(
+        &mut writer,
+        "Ram {}%\n{}/{}MB",
+        stats.memory_percent(),
+        stats.memory_usage,
+        stats.total_memory
+    );
+    let _ = Text::with_baseline(
+        writer.as_str(),
+        Point::zero(),
+        palette.text(TEXT_FONT),
+        Baseline::Top,
+    )
+    .draw(display);
+    draw_bar(display, palette, stats.memory_percent());
+}
+
+/// Which host the page is for, in the gap above the bar
+fn draw_host_name<D: Canvas>(display: &mut D, palette: &Palette<D::Color>, name: &str) {
+    let _ = Text::with_baseline(
+        name,
+        Point::new(0, 30),
+        palette.text(SMALL_FONT),
+        Baseline::Top,
+    )
+    .draw(display);
+}
+
+/// A column per host with its name and CPU and memory usage. The name of a host over one of the
+/// alert thresholds is inverted
+fn draw_side_by_side<D: Canvas>(
+    display: &mut D,
+    palette: &Palette<D::Color>,
+    hosts: &[Host],
+    settings: &DeviceSettings,
+) {
+    let width = 128 / hosts.len() as i32;
+    for (column, host) in hosts.iter().enumerate() {
+        let left = column as i32 * width;
+        let buffer = &mut [0u8; 21];
+        let mut name = TextWriter::new(&mut buffer[..(width as usize - 2) / 6]);
+        let _ = write!(&mut name, "{}", host.stats.host_name);
+        let style = match host.stats.is_alerting(settings) {
+            true => palette.inverted(SMALL_FONT),
+            false => palette.text(SMALL_FONT),
+        };
+        let _ = Text::with_baseline(name.as_str(), Point::new(left, 0), style, Baseline::Top)
+            .draw(display);
+
+        let usage = [
+            ("C", host.stats.cpu_usage),
+            ("M", host.stats.memory_percent()),
+        ];
+        for (row, (label, percent)) in usage.into_iter().enumerate() {
+            let top = 14 + row as i32 * 25;
+            let buffer = &mut [0u8; 8];
+            let mut writer = TextWriter::new(buffer);
+            let _ = write!(&mut writer, "{}{:>3}%", label, percent);
+            let _ = Text::with_baseline(
+                writer.as_str(),
+                Point::new(left, top),
+                palette.text(SMALL_FONT),
+                Baseline::Top,
+            )
+            .draw(display);
+            draw_small_bar(
+                display,
+                palette,
+                Point::new(left, top + 11),
+                width - 3,
+                percent,
+            );
+        }
+    }
+}
+
+/// An 8 pixel high bar for the side by side columns
+fn draw_small_bar<D: Canvas>(
+    display: &mut D,
+    palette: &Palette<D::Color>,
+    top_left: Point,
+    width: i32,
+    percent: u8,
+) {
+    let _ = Rectangle::new(top_left, Size::new(width as u32, 8))
+        .into_styled(PrimitiveStyle::with_stroke(palette.text, 1))
+        .draw(display);
+    let filled = (width - 4) as u32 * percent.min(100) as u32 / 100;
+    let _ = Rectangle::new(top_left + Point::new(2, 2), Size::new(filled, 4))
+        .into_styled(PrimitiveStyle::with_fill(palette.load(percent)))
+        .draw(display);
+}
+
+fn draw_test_pattern<D: Canvas>(display: &mut D, palette: &Palette<D::Color>) {
+    for y in (0..64).step_by(8) {
+        for x in (0..128).step_by(8) {
+            if (x + y) / 8 % 2 == 0 {
+                let _ = Rectangle::new(Point::new(x, y), Size::new(8, 8))
+                    .into_styled(PrimitiveStyle::with_fill(palette.text))
+                    .draw(display);
+            }
+        }
+    }
+}
+
+fn draw_disconnected<D: Canvas>(display: &mut D, palette: &Palette<D::Color>) {
+    let _ = Text::with_baseline(
+        "Cannot connect :(",
+        Point::new(0, 13),
+        palette.text(TEXT_FONT),
+        Baseline::Top,
+    )
+    .draw(display);
+}
+
+fn draw_boot_screen<D: Canvas>(display: &mut D, palette: &Palette<D::Color>, name: &str) {
+    let bmp_logo_data = include_bytes!("../pictures/logo-poststation.bmp");
+    if let Ok(bmp_logo) = Bmp::<BinaryColor>::from_slice(bmp_logo_data) {
+        //The logo is one bit, drawn in the text color
+        let _ = display.draw_iter(bmp_logo.pixels().map(|Pixel(point, color)| {
+            let color = match color {
+                BinaryColor::On => palette.text,
+                BinaryColor::Off => palette.background,
+            };
+            Pixel(point, color)
+        }));
+    }
+    if !name.is_empty() {
+        let _ = Text::with_baseline(
+            name,
+            Point::new(0, 64),
+            palette.inverted(SMALL_FONT),
+            Baseline::Bottom,
+        )
+        .draw(display);
+    }
+}
+
+/// A bar across the top with the icon and as much of the text as fits on one line
+fn draw_banner<D: Canvas>(
+    display: &mut D,
+    palette: &Palette<D::Color>,
+    notification: &Notification,
+) {
+    let screen_width = display.bounding_box().size.width;
+    let _ = Rectangle::new(Point::zero(), Size::new(screen_width, 12))
+        .into_styled(PrimitiveStyle::with_fill(palette.highlight))
+        .draw(display);
+    let mut x = 1;
+    if let Some(icon) = notification.icon {
+        draw_icon(display, icon, Point::new(1, 1), palette.background);
+        x = 13;
+    }
+    let buffer = &mut [0u8; 42];
+    let width = ((screen_width as i32 - x) as usize / 6).min(buffer.len());
+    let mut writer = TextWriter::new(&mut buffer[..width]);
+    let _ = write!(&mut writer, "{}", notification.text);
+    let _ = Text::with_baseline(
+        writer.as_str(),
+        Point::new(x, 1),
+        palette.inverted(SMALL_FONT),
+        Baseline::Top,
+    )
+    .draw(display);
+}
+
+/// The icon centered at the top and the text wrapped over the rest of the screen
+fn draw_full_notification<D: Canvas>(
+    display: &mut D,
+    palette: &Palette<D::Color>,
+    notification: &Notification,
+) {
+    let mut top = 0;
+    if let Some(icon) = notification.icon {
+        draw_icon(display, icon, Point::new(59, 0), palette.highlight);
+        top = 12;
+    }
+    let buffer = &mut [0u8; 80];
+    let mut writer = TextWriter::new(buffer);
+    let lines = (64 - top) as usize / 13;
+    write_wrapped(&mut writer, &notification.text, 16, lines);
+    let _ = Text::with_baseline(
+        writer.as_str(),
+        Point::new(0, top),
+        palette.text(TEXT_FONT),
+        Baseline::Top,
+    )
+    .draw(display);
+}
+
+/// Writes `text` with line breaks between words so no line is longer than `width`.
+/// Words longer than a line are split, and text past `max_lines` is replaced by the ellipsis
+fn write_wrapped(out: &mut TextWriter, text: &str, width: usize, max_lines: usize) {
+    let mut line = 1;
+    let mut column = 0;
+    for word in text.split_whitespace() {
+        let length = word.chars().count();
+        if column > 0 && column + 1 + length <= width {
+            let _ = out.write_char(' ');
+            column += 1;
+        } else if column > 0 {
+            if line == max_lines {
+                let _ = out.write_str(ELLIPSIS);
+                return;
+            }
+            let _ = out.write_char('\n');
+            line += 1;
+            column = 0;
+        }
+        for c in word.chars() {
+            if column == width {
+                if line == max_lines {
+                    let _ = out.write_str(ELLIPSIS);
+                    return;
+                }
+                let _ = out.write_char('\n');
+                line += 1;
+                column = 0;
+            }
+            let _ = out.write_char(c);
+            column += 1;
+        }
+    }
+}
+
+/// A 10x10 picture with its top left corner at `at`
+fn draw_icon<D: Canvas>(display: &mut D, icon: Icon, at: Point, color: D::Color) {
+    let stroke = PrimitiveStyle::with_stroke(color, 1);
+    let p = |x, y| at + Point::new(x, y);
+    let _ = match icon {
+        Icon::Info => Circle::new(at, 10)
+            .into_styled(stroke)
+            .draw(display)
+            .and_then(|_| {
+                Line::new(p(4, 4), p(4, 7))
+                    .into_styled(stroke)
+                    .draw(display)
+            })
+            .and_then(|_| Pixel(p(4, 2), color).draw(display)),
+        Icon::Success => Line::new(p(1, 5), p(4, 8))
+            .into_styled(stroke)
+            .draw(display)
+            .and_then(|_| {
+                Line::new(p(4, 8), p(9, 1))
+                    .into_styled(stroke)
+                    .draw(display)
+            }),
+        Icon::Warning => Triangle::new(p(4, 0), p(0, 9), p(9, 9))
+            .into_styled(stroke)
+            .draw(display)
+            .and_then(|_| {
+                Line::new(p(4, 3), p(4, 6))
+                    .into_styled(stroke)
+                    .draw(display)
+            })
+            .and_then(|_| Pixel(p(4, 8), color).draw(display)),
+        Icon::Error => Circle::new(at, 10)
+            .into_styled(stroke)
+            .draw(display)
+            .and_then(|_| {
+                Line::new(p(3, 3), p(6, 6))
+                    .into_styled(stroke)
+                    .draw(display)
+            })
+            .and_then(|_| {
+                Line::new(p(6, 3), p(3, 6))
+                    .into_styled(stroke)
+                    .draw(display)
+            }),
+    };
+}
+
+/// The time large in the middle with the date below it
+fn draw_clock<D: Canvas>(display: &mut D, palette: &Palette<D::Color>, time: &LocalTime) {
+    let buffer = &mut [0u8; 16];
+    let mut writer = TextWriter::new(buffer);
+    let _ = write!(
+        &mut writer,
+        "{:02}:{:02}:{:02}",
+        time.hour, time.minute, time.second
+    );
+    let _ = Text::with_alignment(
+        writer.as_str(),
+        Point::new(64, 30),
+        palette.text(CLOCK_FONT),
+        Alignment::Center,
+    )
+    .draw(display);
+
+    writer.clear();
+    let _ = write!(
+        &mut writer,
+        "{} {}-{:02}-{:02}",
+        time.weekday_name(),
+        time.year,
+        time.month,
+        time.day
+    );
+    let _ = Text::with_alignment(
+        writer.as_str(),
+        Point::new(64, 50),
+        palette.text(SMALL_FONT),
+        Alignment::Center,
+    )
+    .draw(display);
+}
+
+fn draw_waiting_for_time<D: Canvas>(display: &mut D, palette: &Palette<D::Color>) {
+    let _ = Text::with_baseline(
+        "Waiting for\nthe time",
+        Point::zero(),
+        palette.text(TEXT_FONT),
+        Baseline::Top,
+    )
+    .draw(display);
+}
+
+/// One metric of `host` in seven-segment digits as high as the screen allows, with what it is
+/// in small text above them. `averaged` shows the last [`HISTORY_STEP`] average instead of the
+/// latest value
+fn draw_large_digits<D: Canvas>(
+    display: &mut D,
+    palette: &Palette<D::Color>,
+    host: &Host,
+    metric: Metric,
+    averaged: bool,
+) {
+    let (label, latest, history) = match metric {
+        Metric::Cpu => ("CPU", host.stats.cpu_usage, &host.history.cpu),
+        Metric::Memory => ("Ram", host.stats.memory_percent(), &host.history.memory),
+    };
+    let percent = match averaged {
+        true => history.back().copied().unwrap_or(latest),
+        false => latest,
+    };
+
+    let buffer = &mut [0u8; 40];
+    let mut writer = TextWriter::new(buffer);
+    let _ = write!(&mut writer, "{} {}", label, host.stats.host_name);
+    let _ = Text::with_baseline(
+        writer.as_str(),
+        Point::zero(),
+        palette.text(SMALL_FONT),
+        Baseline::Top,
+    )
+    .draw(display);
+
+    let size = display.bounding_box().size;
+    let height = size.height as i32 - 12;
+    let width = height / 2;
+    let gap = height / 8;
+    let buffer = &mut [0u8; 4];
+    let mut digits = TextWriter::new(buffer);
+    let _ = write!(&mut digits, "{}", percent.min(100));
+    //The digits and the percent sign after them, centered
+    let total = digits.as_str().len() as i32 * (width + gap) + 10;
+    let mut x = (size.width as i32 - total) / 2;
+    let color = palette.load(percent);
+    for digit in digits.as_str().bytes() {
+        let area = Rectangle::new(Point::new(x, 12), Size::new(width as u32, height as u32));
+        draw_segment_digit(display, area, digit - b'0', color);
+        x += width + gap;
+    }
+    let _ = Text::with_baseline(
+        "%",
+        Point::new(x, size.height as i32),
+        palette.text(CLOCK_FONT),
+        Baseline::Bottom,
+    )
+    .draw(display);
+}
+
+/// A gauge each for CPU and memory side by side, with the host name below them if it fits.
+/// `averaged` shows the last [`HISTORY_STEP`] average and the peak of the last hour instead of
+/// the latest value and the held peak
+fn draw_gauges<D: Canvas>(
+    display: &mut D,
+    palette: &Palette<D::Color>,
+    host: &Host,
+    peak_hold: bool,
+    averaged: bool,
+) {
+    let size = display.bounding_box().size;
+    let (width, height) = (size.width as i32, size.height as i32);
+    let radius = (width / 4 - 2).min(height - 16);
+    let gauges = [
+        (
+            "CPU",
+            host.stats.cpu_usage,
+            &host.history.cpu,
+            &host.cpu_peak,
+        ),
+        (
+            "Ram",
+            host.stats.memory_percent(),
+            &host.history.memory,
+            &host.memory_peak,
+        ),
+    ];
+    for (column, (label, latest, history, peak)) in gauges.into_iter().enumerate() {
+        let (percent, peak) = match averaged {
+            true => (
+                history.back().copied().unwrap_or(latest),
+                history.iter().copied().max().unwrap_or(latest),
+            ),
+            false => (latest, peak.percent),
+        };
+        let center = Point::new(width / 4 + column as i32 * width / 2, radius + 2);
+        draw_gauge(
+            display,
+            palette,
+            center,
+            radius,
+            percent,
+            peak_hold.then_some(peak),
+        );
+
+        let buffer = &mut [0u8; 8];
+        let mut writer = TextWriter::new(buffer);
+        let _ = write!(&mut writer, "{} {}%", label, percent);
+        let _ = Text::with_baseline(
+            writer.as_str(),
+            center + Point::new(0, 4),
+            palette.text(SMALL_FONT),
+            Baseline::Top,
+        )
+        .set_alignment(Alignment::Center)
+        .draw(display);
+    }
+    if height - radius >= 28 {
+        let _ = Text::with_baseline(
+            &host.stats.host_name,
+            Point::new(width / 2, height),
+            palette.text(SMALL_FONT),
+            Baseline::Bottom,
+        )
+        .set_alignment(Alignment::Center)
+        .draw(display);
+    }
+}
+
+/// A half circle from 0% on the left to 100% on the right with a tick every 10%, the needle at
+/// `percent` and a marker at `peak`
+fn draw_gauge<D: Canvas>(
+    display: &mut D,
+    palette: &Palette<D::Color>,
+    center: Point,
+    radius: i32,
+    percent: u8,
+    peak: Option<u8>,
+) {
+    let outline = PrimitiveStyle::with_stroke(palette.text, 1);
+    let _ = Arc::with_center(center, radius as u32 * 2, 180.0.deg(), 180.0.deg())
+        .into_styled(outline)
+        .draw(display);
+    for tick in (0..=100).step_by(10) {
+        let length = match tick % 50 {
+            0 => radius / 4,
+            _ => radius / 8,
+        };
+        let _ = Line::new(
+            gauge_point(center, radius - length, tick),
+            gauge_point(center, radius, tick),
+        )
+        .into_styled(outline)
+        .draw(display);
+    }
+    if let Some(peak) = peak {
+        let _ = Line::new(
+            gauge_point(center, radius - radius / 3, peak),
+            gauge_point(center, radius, peak),
+        )
+        .into_styled(PrimitiveStyle::with_stroke(palette.highlight, 2))
+        .draw(display);
+    }
+    let _ = Line::new(center, gauge_point(center, radius - radius / 6, percent))
+        .into_styled(PrimitiveStyle::with_stroke(palette.load(percent), 2))
+        .draw(display);
+    let _ = Circle::with_center(center, 5)
+        .into_styled(PrimitiveStyle::with_fill(palette.text))
+        .draw(display);
+}
+
+/// The point `radius` away from `center` in the direction of `percent` on a gauge
+fn gauge_point(center: Point, radius: i32, percent: u8) -> Point {
+    //Degrees from the left, clockwise over the top
+    let angle = 180 * percent.min(100) as i32 / 100;
+    let (sin, cos) = match angle {
+        0..=90 => (sine(angle), sine(90 - angle)),
+        _ => (sine(angle), -sine(angle - 90)),
+    };
+    center + Point::new(-radius * cos / 1024, -radius * sin / 1024)
+}
+
+/// The sine of `degrees` from 0 to 180 times 1024, using Bhaskara's approximation since there is
+/// no floating point trigonometry without std
+fn sine(degrees: i32) -> i32 {
+    let x = degrees * (180 - degrees);
+    4 * 1024 * x / (40_500 - x)
+}
+
+/// A seven-segment `digit` filling `area`
+fn draw_segment_digit<D: Canvas>(display: &mut D, area: Rectangle, digit: u8, color: D::Color) {
+    let (x, y) = (area.top_left.x, area.top_left.y);
+    let (w, h) = (area.size.width as i32, area.size.height as i32);
+    let t = (h / 9).max(2);
+    let middle = (h - t) / 2;
+    let segments = [
+        (x + t, y, w - 2 * t, t),
+        (x + w - t, y + t, t, middle - t),
+        (x + w - t, y + middle + t, t, h - middle - 2 * t),
+        (x + t, y + h - t, w - 2 * t, t),
+        (x, y + middle + t, t, h - middle - 2 * t),
+        (x, y + t, t, middle - t),
+        (x + t, y + middle, w - 2 * t, t),
+    ];
+    let lit = SEGMENTS[digit.min(9) as usize];
+    for (bit, (left, top, width, height)) in segments.into_iter().enumerate() {
+        if lit & (1 << bit) != 0 {
+            let _ = Rectangle::new(
+                Point::new(left, top),
+                Size::new(width as u32, height as u32),
+            )
+            .into_styled(PrimitiveStyle::with_fill(color))
+            .draw(display);
+        }
+    }
+}
+
+/// A horizontal bar along the bottom of the screen filled to `percent`
+fn draw_bar<D: Canvas>(display: &mut D, palette: &Palette<D::Color>, percent: u8) {
+    let outline = Rectangle::new(Point::new(0, 44), Size::new(128, 16));
+    let _ = outline
+        .into_styled(PrimitiveStyle::with_stroke(palette.text, 1))
+        .draw(display);
+    let width = 124 * percent.min(100) as u32 / 100;
+    let _ = Rectangle::new(Point::new(2, 46), Size::new(width, 12))
+        .into_styled(PrimitiveStyle::with_fill(palette.load(percent)))
+        .draw(display);
+}
+
+/// A small inverted "!" in the top right corner
+fn draw_alert<D: Canvas>(display: &mut D, palette: &Palette<D::Color>) {
+    let right = display.bounding_box().size.width as i32;
+    let _ = Text::with_baseline(
+        "!",
+        Point::new(right - 6, 0),
+        palette.inverted(SMALL_FONT),
+        Baseline::Top,
+    )
+    .draw(display);
+}
+
+/// Hours and minutes large in the middle with the date below, for e-paper
+fn draw_large_clock<D: Canvas>(display: &mut D, palette: &Palette<D::Color>, time: &LocalTime) {
+    let center = display.bounding_box().center();
+    let buffer = &mut [0u8; 16];
+    let mut writer = TextWriter::new(buffer);
+    let _ = write!(&mut writer, "{:02}:{:02}", time.hour, time.minute);
+    let _ = Text::with_alignment(
+        writer.as_str(),
+        center,
+        palette.text(CLOCK_FONT),
+        Alignment::Center,
+    )
+    .draw(display);
+
+    writer.clear();
+    let _ = write!(
+        &mut writer,
+        "{} {}-{:02}-{:02}",
+        time.weekday_name(),
+        time.year,
+        time.month,
+        time.day
+    );
+    let _ = Text::with_alignment(
+        writer.as_str(),
+        center + Point::new(0, 20),
+        palette.text(TEXT_FONT),
+        Alignment::Center,
+    )
+    .draw(display);
+}
+
+/// The host name, then a row each for CPU and memory with the last average in large numbers
+/// next to a graph of the last hour
+fn draw_history<D: Canvas>(display: &mut D, palette: &Palette<D::Color>, host: &Host) {
+    const GRAPH_LEFT: i32 = 52;
+    let size = display.bounding_box().size;
+    let _ = Text::with_baseline(
+        &host.stats.host_name,
+        Point::zero(),
+        palette.text(SMALL_FONT),
+        Baseline::Top,
+    )
+    .draw(display);
+
+    let rows = [
+        ("CPU", host.stats.cpu_usage, &host.history.cpu),
+        ("Ram", host.stats.memory_percent(), &host.history.memory),
+    ];
+    let row_height = (size.height as i32 - 12) / 2;
+    for (row, (label, latest, history)) in rows.into_iter().enumerate() {
+        let top = 12 + row as i32 * row_height;
+        let _ = Text::with_baseline(
+            label,
+            Point::new(0, top),
+            palette.text(SMALL_FONT),
+            Baseline::Top,
+        )
+        .draw(display);
+        let average = history.back().copied().unwrap_or(latest);
+        let buffer = &mut [0u8; 8];
+        let mut writer = TextWriter::new(buffer);
+        let _ = write!(&mut writer, "{}%", average);
+        let _ = Text::with_baseline(
+            writer.as_str(),
+            Point::new(0, top + 10),
+            palette.text(CLOCK_FONT),
+            Baseline::Top,
+        )
+        .draw(display);
+
+        let area = Rectangle::new(
+            Point::new(GRAPH_LEFT, top),
+            Size::new(size.width - GRAPH_LEFT as u32, row_height as u32 - 2),
+        );
+        draw_graph(display, palette, area, history);
+    }
+}
+
+/// An outlined graph of `history` filling `area`, the newest point on the right
+fn draw_graph<D: Canvas>(
+    display: &mut D,
+    palette: &Palette<D::Color>,
+    area: Rectangle,
+    history: &Deque<u8, HISTORY_LEN>,
+) {
+    let _ = area
+        .into_styled(PrimitiveStyle::with_stroke(palette.text, 1))
+        .draw(display);
+    let inside = area.offset(-1);
+    let (width, height) = (inside.size.width as usize, inside.size.height as i32);
+    let bottom = inside.top_left.y + height - 1;
+    let points: Vec<u8, HISTORY_LEN> = history.iter().copied().collect();
+    for column in 0..width {
+        //Each point gets the same share of the width, whether the history is full or not
+        let age = HISTORY_LEN - 1 - column * HISTORY_LEN / width;
+        let Some(index) = points.len().checked_sub(age + 1) else {
+            continue;
+        };
+        let percent = points[index];
+        let filled = height * percent.min(100) as i32 / 100;
+        if filled == 0 {
+            continue;
+        }
+        let x = inside.top_left.x + column as i32;
+        let _ = Line::new(Point::new(x, bottom), Point::new(x, bottom + 1 - filled))
+            .into_styled(PrimitiveStyle::with_stroke(palette.load(percent), 1))
+            .draw(display);
+    }
+}
@@ -0,0 +1,347 @@
+//! A 2.13" black and white e-paper panel (SSD1680, 250x122) on SPI, when built with the `epaper`
+//! feature.
+//!
+//! A refresh takes seconds and a full one flashes the whole panel, so they are budgeted: at most
+//! one every [`MIN_REFRESH`] unless the menu is open, and partial refreshes that only redraw the
+//! changed rows in between full ones that clear the ghosting partial refreshes leave behind.
+//! [`crate::display`] draws layouts meant for this, large numbers and graphs of the last hour.
+
+use crate::display::changed_columns;
+use core::convert::Infallible;
+use core::ops::Range;
+use embassy_rp::gpio::{Input, Output};
+use embassy_rp::peripherals::SPI0;
+use embassy_rp::spi::{self, Spi};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+use icd::{DeviceSettings, Rotation};
+use static_cell::ConstStaticCell;
+
+#[cfg(feature = "spi-display")]
+compile_error!("The epaper feature can't be combined with a color display feature");
+
+/// The panel in landscape, as the pages are drawn
+const WIDTH: usize = 250;
+const HEIGHT: usize = 122;
+/// The controller's memory is portrait: a row per landscape column, a bit per landscape row with
+/// the rows padded to whole bytes
+const ROW_BYTES: usize = HEIGHT.div_ceil(8);
+const ROWS: usize = WIDTH;
+
+/// Shortest time between two refreshes while the menu is closed
+const MIN_REFRESH: Duration = Duration::from_secs(60);
+/// A full refresh after this many partial ones, or after [`FULL_EVERY`] if that comes first
+const PARTIALS_PER_FULL: u32 = 30;
+const FULL_EVERY: Duration = Duration::from_secs(60 * 60);
+/// A full refresh takes about 3 seconds, a partial one under 1
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Commands of the SSD1680
+const SOFT_RESET: u8 = 0x12;
+const DRIVER_OUTPUT: u8 = 0x01;
+const DATA_ENTRY_MODE: u8 = 0x11;
+const RAM_X_RANGE: u8 = 0x44;
+const RAM_Y_RANGE: u8 = 0x45;
+const RAM_X_COUNTER: u8 = 0x4E;
+const RAM_Y_COUNTER: u8 = 0x4F;
+const BORDER: u8 = 0x3C;
+const TEMPERATURE_SENSOR: u8 = 0x18;
+/// What the panel shows after the next refresh
+const WRITE_NEW: u8 = 0x24;
+/// What it showed before, partial refreshes only change the pixels that differ from it
+const WRITE_OLD: u8 = 0x26;
+const UPDATE_SEQUENCE: u8 = 0x22;
+const ACTIVATE: u8 = 0x20;
+/// Sequences for [`UPDATE_SEQUENCE`]
+const FULL_REFRESH: u8 = 0xF7;
+const PARTIAL_REFRESH: u8 = 0xFC;
+
+pub fn spi_config() -> spi::Config {
+    let mut config = spi::Config::default();
+    config.frequency = 10_000_000;
+    config
+}
+
+/// Pixels laid out like the controller's memory, where a set bit is white
+#[derive(Clone)]
+pub struct Frame {
+    bytes: [u8; ROW_BYTES * ROWS],
+}
+
+impl Frame {
+    const fn new() -> Self {
+        Frame {
+            bytes: [0xFF; ROW_BYTES * ROWS],
+        }
+    }
+
+    /// Memory row `row` as it is sent, turned around if the panel is mounted upside down
+    fn row(&self, row: usize, flipped: bool) -> [u8; ROW_BYTES] {
+        if !flipped {
+            let mut out = [0; ROW_BYTES];
+            out.copy_from_slice(&self.bytes[row * ROW_BYTES..(row + 1) * ROW_BYTES]);
+            return out;
+        }
+        let source = &self.bytes[(ROWS - 1 - row) * ROW_BYTES..(ROWS - row) * ROW_BYTES];
+        let mut out = [0xFF; ROW_BYTES];
+        for bit in 0..HEIGHT {
+            let from = HEIGHT - 1 - bit;
+            if source[from / 8] & (0x80 >> (from % 8)) == 0 {
+                out[bit / 8] &= !(0x80 >> (bit % 8));
+            }
+        }
+        out
+    }
+}
+
+impl OriginDimensions for Frame {
+    fn size(&self) -> Size {
+        Size::new(WIDTH as u32, HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for Frame {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<BinaryColor>>,
+    {
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (usize::try_from(point.x), usize::try_from(point.y)) else {
+                continue;
+            };
+            if x >= WIDTH || y >= HEIGHT {
+                continue;
+            }
+            //Landscape columns run down the memory rows, landscape rows right to left in a row
+            let bit = HEIGHT - 1 - y;
+            let byte = &mut self.bytes[x * ROW_BYTES + bit / 8];
+            match color {
+                BinaryColor::On => *byte &= !(0x80 >> (bit % 8)),
+                BinaryColor::Off => *byte |= 0x80 >> (bit % 8),
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: BinaryColor) -> Result<(), Self::Error> {
+        self.bytes.fill(match color {
+            BinaryColor::On => 0,
+            BinaryColor::Off => 0xFF,
+        });
+        Ok(())
+    }
+}
+
+/// Owns the e-paper panel. Only the render task uses it
+pub struct Panel {
+    spi: Spi<'static, SPI0, spi::Async>,
+    cs: Output<'static>,
+    /// Low while sending a command, high for its arguments and pixels
+    dc: Output<'static>,
+    reset: Output<'static>,
+    /// High while a refresh is running
+    busy: Input<'static>,
+    /// Drawn into by the ui. Kept in statics like the color panel's, they are 4 KB each
+    frame: &'static mut Frame,
+    /// What the panel is showing, if `in_sync`
+    shown: &'static mut Frame,
+    /// False until the first full refresh after an init
+    in_sync: bool,
+    flipped: bool,
+    /// What was last sent to the panel, `None` after an init
+    configured: Option<Rotation>,
+    last_refresh: Option<Instant>,
+    last_full: Instant,
+    partials: u32,
+}
+
+impl Panel {
+    /// Can only be called once, the frames are statics
+    pub fn new(
+        spi: Spi<'static, SPI0, spi::Async>,
+        cs: Output<'static>,
+        dc: Output<'static>,
+        reset: Output<'static>,
+        busy: Input<'static>,
+    ) -> Self {
+        static FRAME: ConstStaticCell<Frame> = ConstStaticCell::new(Frame::new());
+        static SHOWN: ConstStaticCell<Frame> = ConstStaticCell::new(Frame::new());
+        Panel {
+            spi,
+            cs,
+            dc,
+            reset,
+            busy,
+            frame: FRAME.take(),
+            shown: SHOWN.take(),
+            in_sync: false,
+            flipped: false,
+            configured: None,
+            last_refresh: None,
+            last_full: Instant::now(),
+            partials: 0,
+        }
+    }
+
+    /// Resets and sets up the panel, returns false if it stays busy
+    pub async fn init(&mut self) -> bool {
+        self.reset.set_low();
+        Timer::after_millis(10).await;
+        self.reset.set_high();
+        Timer::after_millis(10).await;
+
+        let last_row = (ROWS - 1) as u16;
+        let setup: [(u8, &[u8]); 6] = [
+            (
+                DRIVER_OUTPUT,
+                &[last_row as u8, (last_row >> 8) as u8, 0x00],
+            ),
+            (DATA_ENTRY_MODE, &[0x03]),
+            (RAM_X_RANGE, &[0x00, (ROW_BYTES - 1) as u8]),
+            (BORDER, &[0x05]),
+            (TEMPERATURE_SENSOR, &[0x80]),
+            (RAM_X_COUNTER, &[0x00]),
+        ];
+        if self.command(SOFT_RESET, &[]).await.is_err() || self.wait_busy().await.is_err() {
+            return false;
+        }
+        for (command, args) in setup {
+            if self.command(command, args).await.is_err() {
+                return false;
+            }
+        }
+        self.in_sync = false;
+        self.configured = None;
+        true
+    }
+
+    pub fn frame(&mut self) -> &mut Frame {
+        self.frame
+    }
+
+    /// When the next refresh is due, `None` if it can happen now
+    pub fn next_refresh(&self) -> Option<Instant> {
+        let due = self.last_refresh? + MIN_REFRESH;
+        (due > Instant::now()).then_some(due)
+    }
+
+    /// E-paper keeps its picture without power, there is nothing to switch off
+    pub async fn set_on(&mut self, _on: bool) -> Result<(), ()> {
+        Ok(())
+    }
+
+    /// Turns the picture around if the rotation changed. The panel has no brightness
+    pub async fn configure(&mut self, settings: &DeviceSettings) -> Result<(), ()> {
+        if self.configured != Some(settings.rotation) {
+            self.flipped = settings.rotation == Rotation::Flipped;
+            self.in_sync = false;
+            self.configured = Some(settings.rotation);
+        }
+        Ok(())
+    }
+
+    /// Refreshes the rows that changed, or the whole panel if a full refresh is due.
+    /// Returns how many bytes of pixels went out
+    pub async fn flush(&mut self) -> Result<usize, ()> {
+        let full = !self.in_sync
+            || self.partials >= PARTIALS_PER_FULL
+            || self.last_full.elapsed() >= FULL_EVERY;
+        let rows = match full {
+            true => 0..ROWS,
+            false => match changed_columns(&self.frame.bytes, &self.shown.bytes) {
+                Some(bytes) => bytes.start / ROW_BYTES..bytes.end.div_ceil(ROW_BYTES),
+                None => return Ok(0),
+            },
+        };
+        //The memory rows as sent, which are the other way around when flipped
+        let rows = match self.flipped {
+            true => ROWS - rows.end..ROWS - rows.start,
+            false => rows,
+        };
+
+        self.write_rows(WRITE_NEW, rows.clone()).await?;
+        if full {
+            self.write_rows(WRITE_OLD, rows.clone()).await?;
+        }
+        let sequence = match full {
+            true => FULL_REFRESH,
+            false => PARTIAL_REFRESH,
+        };
+        self.command(UPDATE_SEQUENCE, &[sequence]).await?;
+        self.command(ACTIVATE, &[]).await?;
+        self.wait_busy().await?;
+        //The next partial refresh compares against this one
+        if !full {
+            self.write_rows(WRITE_OLD, rows.clone()).await?;
+        }
+
+        let now = Instant::now();
+        self.last_refresh = Some(now);
+        if full {
+            self.last_full = now;
+            self.partials = 0;
+        } else {
+            self.partials += 1;
+        }
+        self.shown.clone_from(self.frame);
+        self.in_sync = true;
+        Ok(rows.len() * ROW_BYTES)
+    }
+
+    /// Writes `rows` of the frame into `memory`, one of [`WRITE_NEW`] and [`WRITE_OLD`]
+    async fn write_rows(&mut self, memory: u8, rows: Range<usize>) -> Result<(), ()> {
+        let (first, last) = (rows.start as u16, (rows.end - 1) as u16);
+        let range = [
+            first as u8,
+            (first >> 8) as u8,
+            last as u8,
+            (last >> 8) as u8,
+        ];
+        self.command(RAM_Y_RANGE, &range).await?;
+        self.command(RAM_X_COUNTER, &[0x00]).await?;
+        self.command(RAM_Y_COUNTER, &range[..2]).await?;
+
+        self.start(memory).await?;
+        for row in rows {
+            let bytes = self.frame.row(row, self.flipped);
+            let sent = self.spi.write(&bytes).await.map_err(drop);
+            if sent.is_err() {
+                self.cs.set_high();
+                return sent;
+            }
+        }
+        self.cs.set_high();
+        Ok(())
+    }
+
+    async fn wait_busy(&mut self) -> Result<(), ()> {
+        with_timeout(BUSY_TIMEOUT, self.busy.wait_for_low())
+            .await
+            .map_err(drop)
+    }
+
+    async fn command(&mut self, command: u8, args: &[u8]) -> Result<(), ()> {
+        self.start(command).await?;
+        let sent = match args.is_empty() {
+            true => Ok(()),
+            false => self.spi.write(args).await.map_err(drop),
+        };
+        self.cs.set_high();
+        sent
+    }
+
+    /// Sends `command` and leaves the panel selected for its arguments
+    async fn start(&mut self, command: u8) -> Result<(), ()> {
+        self.cs.set_low();
+        self.dc.set_low();
+        let sent = self.spi.write(&[command]).await.map_err(drop);
+        self.dc.set_high();
+        if sent.is_err() {
+            self.cs.set_high();
+        }
+        sent
+    }
+}
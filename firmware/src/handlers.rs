@@ -1,27 +1,18 @@
 use crate::{
     app::{AppTx, Context, TaskContext},
-    io::Cursor,
+    buzzer, clock,
+    display::{self, Stats},
+    led, logging, rgb,
 };
-use core::fmt::Write;
 use core::sync::atomic::{compiler_fence, Ordering};
 use embassy_time::{Instant, Timer};
-use embedded_graphics::{
-    mono_font::{
-        ascii::{self},
-        MonoTextStyle, MonoTextStyleBuilder,
-    },
-    pixelcolor::BinaryColor,
-    prelude::*,
-    text::{Baseline, Text},
+use icd::{
+    DeviceName, DeviceSettings, DisplayStatus, LastPanic, LedState, LogLevel, Metric, MetricChoice,
+    Notification, OtaBegin, OtaChunk, OtaResult, PlayTone, QrCode, RgbEffect, SelfTestReport,
+    SleepEndpoint, SleepMillis, SleptMillis, SysInfo, TimeSync,
 };
-use icd::{LedState, SleepEndpoint, SleepMillis, SleptMillis, SysInfo};
 use postcard_rpc::{header::VarHeader, server::Sender};
 
-const TEXT_STYLE: MonoTextStyle<'static, BinaryColor> = MonoTextStyleBuilder::new()
-    .font(&ascii::FONT_8X13)
-    .text_color(BinaryColor::On)
-    .build();
-
 /// This is an example of a BLOCKING handler.
 pub fn unique_id(context: &mut Context, _header: VarHeader, _arg: ()) -> u64 {
     context.unique_id
@@ -38,39 +29,143 @@ pub fn picoboot_reset(_context: &mut Context, _header: VarHeader, _arg: ()) {
 
 /// Also a BLOCKING handler
 pub fn set_led(context: &mut Context, _header: VarHeader, arg: LedState) {
-    match arg {
-        LedState::Off => context.led.set_low(),
-        LedState::On => context.led.set_high(),
-    }
+    context.led_state = arg;
+    led::LED_STATE.signal(arg);
 }
 
 pub fn get_led(context: &mut Context, _header: VarHeader, _arg: ()) -> LedState {
-    match context.led.is_set_low() {
-        true => LedState::Off,
-        false => LedState::On,
-    }
+    context.led_state
+}
+
+pub fn set_rgb_led(context: &mut Context, _header: VarHeader, arg: RgbEffect) {
+    context.rgb_effect = arg;
+    rgb::EFFECT.signal(arg);
+}
+
+pub fn get_rgb_led(context: &mut Context, _header: VarHeader, _arg: ()) -> RgbEffect {
+    context.rgb_effect
+}
+
+pub fn play_tone(_context: &mut Context, _header: VarHeader, arg: PlayTone) -> bool {
+    buzzer::play(arg)
+}
+
+pub fn get_last_panic(context: &mut Context, _header: VarHeader, _arg: ()) -> LastPanic {
+    context.last_panic.clone()
+}
+
+pub fn set_log_level(_context: &mut Context, _header: VarHeader, arg: LogLevel) {
+    logging::set_level(arg);
+}
+
+pub fn get_log_level(_context: &mut Context, _header: VarHeader, _arg: ()) -> LogLevel {
+    logging::level()
+}
+
+pub fn ota_begin(context: &mut Context, _header: VarHeader, arg: OtaBegin) -> OtaResult {
+    logging::info!("Receiving a {} byte firmware update", arg.size);
+    context.ota.begin(arg)
+}
+
+pub fn ota_write(context: &mut Context, _header: VarHeader, arg: OtaChunk<'_>) -> OtaResult {
+    context.ota.write(arg)
+}
+
+/// Reboots into the new firmware shortly after replying, if it was received correctly
+pub fn ota_finish(context: &mut Context, _header: VarHeader, _arg: ()) -> OtaResult {
+    context.ota.finish()
+}
+
+pub fn confirm_firmware(context: &mut Context, _header: VarHeader, _arg: ()) {
+    context.ota.confirm();
 }
 
 pub async fn set_screen_text<'a>(context: &mut Context, _header: VarHeader, arg: SysInfo<'a>) {
-    context.display.clear_buffer();
-
-    let buffer = &mut [0u8; 1024];
-    let mut cursor = Cursor::new(buffer);
-
-    let _ = write!(
-        &mut cursor,
-        "{}\nCPU:{} {}% \nRam:{}/{}\n\n{}",
-        arg.host_name,
-        arg.cpu_freq_text,
-        arg.cpu_usage,
-        arg.memory_usage,
-        arg.total_memory,
-        arg.scroll_text
-    );
-    Text::with_baseline(cursor.as_str(), Point::zero(), TEXT_STYLE, Baseline::Top)
-        .draw(&mut context.display)
-        .unwrap();
-    let _ = context.display.flush().await;
+    let stats = Stats::from_sys_info(&arg);
+    let mut ui = context.ui.lock().await;
+    ui.show_stats(arg.source, stats);
+    let alerting = ui.is_alerting();
+    //Only sound the alarm when crossing the threshold, not for every frame over it
+    if alerting && !context.alerting {
+        buzzer::alarm();
+    }
+    context.alerting = alerting;
+    rgb::update_status(ui.busiest_cpu(), alerting);
+}
+
+pub async fn get_settings(context: &mut Context, _header: VarHeader, _arg: ()) -> DeviceSettings {
+    context.settings.lock().await.get().clone()
+}
+
+/// Saves the settings to flash and applies them to the display
+pub async fn set_settings(context: &mut Context, _header: VarHeader, arg: DeviceSettings) {
+    if let Err(e) = context.settings.lock().await.save(arg.clone()) {
+        logging::warn!("Could not save settings: {:?}", e);
+    }
+    context.ui.lock().await.apply_settings(arg);
+}
+
+/// Saves the name with the rest of the settings, the boot screen shows it
+pub async fn set_name(context: &mut Context, _header: VarHeader, arg: DeviceName) {
+    let mut settings = context.settings.lock().await;
+    let mut updated = settings.get().clone();
+    updated.name = arg;
+    if let Err(e) = settings.save(updated.clone()) {
+        logging::warn!("Could not save the device name: {:?}", e);
+    }
+    drop(settings);
+    context.ui.lock().await.apply_settings(updated);
+}
+
+pub async fn get_name(context: &mut Context, _header: VarHeader, _arg: ()) -> DeviceName {
+    context.settings.lock().await.get().name.clone()
+}
+
+/// The clock picks this up on its next tick
+pub fn set_time(_context: &mut Context, _header: VarHeader, arg: TimeSync) {
+    clock::sync(arg);
+}
+
+/// Queues a notification, returns false if the queue was full of more important ones
+pub async fn notify(context: &mut Context, _header: VarHeader, arg: Notification) -> bool {
+    let queued = context.ui.lock().await.queue_notification(arg);
+    if !queued {
+        logging::warn!("Notification queue full, dropped one");
+    }
+    queued
+}
+
+/// Switches the metric shown in large digits
+pub async fn show_metric(context: &mut Context, _header: VarHeader, arg: MetricChoice) -> Metric {
+    context.ui.lock().await.show_metric(arg)
+}
+
+/// Puts a QR code on the display, returns false if the text is too long for one
+pub async fn show_qr_code(context: &mut Context, _header: VarHeader, arg: QrCode) -> bool {
+    let shown = context.ui.lock().await.show_qr_code(arg);
+    if !shown {
+        logging::warn!("Text too long for a QR code, not shown");
+    }
+    shown
+}
+
+/// Scans the I2C bus and puts a test pattern on the display
+pub async fn self_test(context: &mut Context, _header: VarHeader, _arg: ()) -> SelfTestReport {
+    let i2c_addresses = display::scan_i2c(context.i2c_bus).await;
+    let test_pattern_sent = display::show_test_pattern(context.ui).await;
+    SelfTestReport {
+        i2c_addresses,
+        display_ready: context.ui.lock().await.status().ready,
+        test_pattern_sent,
+    }
+}
+
+pub async fn get_display_status(
+    context: &mut Context,
+    _header: VarHeader,
+    _arg: (),
+) -> DisplayStatus {
+    context.ui.lock().await.status()
 }
 
 /// This is a SPAWN handler
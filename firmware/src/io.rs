@@ -1,38 +1,135 @@
-///Based off of my favorite implementation of handling strings and formatting from rp2040-panic-usb-boot
+///Started out as my favorite implementation of handling strings and formatting from rp2040-panic-usb-boot
 ///https://github.com/jannic/rp2040-panic-usb-boot/blob/3c83bab22c12c51458a571642d9a214901f5b60e/src/lib.rs#L11
+///
+///Unlike that one, writes never fail. Text that does not fit is cut off at a character boundary and
+///the end is marked with [`ELLIPSIS`], so a partial frame is still safe to render and shows that
+///something is missing.
 
-pub struct Cursor<'a> {
-    pub buf: &'a mut [u8],
+/// Marks text that was cut off. The display fonts are ASCII only, so no '…'
+pub const ELLIPSIS: &str = "...";
+
+pub struct TextWriter<'a> {
+    buf: &'a mut [u8],
     pos: usize,
+    dropped: usize,
 }
 
-impl<'a> Cursor<'a> {
-    pub fn new(buf: &'a mut [u8]) -> Cursor<'a> {
-        Cursor { buf, pos: 0 }
+impl<'a> TextWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> TextWriter<'a> {
+        TextWriter {
+            buf,
+            pos: 0,
+            dropped: 0,
+        }
     }
 
     pub fn clear(&mut self) {
-        //empty the buffer
-        for i in 0..self.buf.len() {
-            self.buf[i] = 0;
-        }
         self.pos = 0;
+        self.dropped = 0;
     }
 
+    /// What was written, ending with [`ELLIPSIS`] if anything was dropped
     pub fn as_str(&self) -> &str {
-        core::str::from_utf8(&self.buf[..self.pos]).unwrap()
+        //Only whole characters are ever copied in, so this does not fail
+        core::str::from_utf8(&self.buf[..self.pos]).unwrap_or("")
+    }
+
+    /// Bytes of text that did not fit, including any that made room for the ellipsis
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.dropped > 0
+    }
+
+    /// Cuts the text off so the ellipsis fits behind it
+    fn truncate(&mut self, s: &str) {
+        let limit = self.buf.len().saturating_sub(ELLIPSIS.len());
+        let end = if self.pos > limit {
+            //What is already written has to make room for the ellipsis
+            let written = self.as_str();
+            let mut end = limit;
+            while !written.is_char_boundary(end) {
+                end -= 1;
+            }
+            self.dropped += self.pos - end + s.len();
+            end
+        } else {
+            let mut take = (limit - self.pos).min(s.len());
+            while !s.is_char_boundary(take) {
+                take -= 1;
+            }
+            self.buf[self.pos..self.pos + take].copy_from_slice(&s.as_bytes()[..take]);
+            self.dropped += s.len() - take;
+            self.pos + take
+        };
+
+        //Buffers smaller than the ellipsis get as much of it as fits
+        let marker_len = ELLIPSIS.len().min(self.buf.len() - end);
+        self.buf[end..end + marker_len].copy_from_slice(&ELLIPSIS.as_bytes()[..marker_len]);
+        self.pos = end + marker_len;
     }
 }
 
-impl core::fmt::Write for Cursor<'_> {
+impl core::fmt::Write for TextWriter<'_> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let len = s.len();
-        if len < self.buf.len() - self.pos {
-            self.buf[self.pos..self.pos + len].clone_from_slice(s.as_bytes());
-            self.pos += len;
-            Ok(())
+        if self.is_truncated() {
+            self.dropped += s.len();
+        } else if s.len() <= self.buf.len() - self.pos {
+            self.buf[self.pos..self.pos + s.len()].copy_from_slice(s.as_bytes());
+            self.pos += s.len();
         } else {
-            Err(core::fmt::Error)
+            self.truncate(s);
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test]
+    fn exact_fill_is_not_truncated() {
+        let mut buf = [0u8; 5];
+        let mut writer = TextWriter::new(&mut buf);
+        let _ = write!(writer, "{}", "hello");
+        assert_eq!(writer.as_str(), "hello");
+        assert!(!writer.is_truncated());
+        assert_eq!(writer.dropped(), 0);
+    }
+
+    #[test]
+    fn cuts_multi_byte_characters_whole() {
+        //"é" is two bytes, only the first would fit in front of the ellipsis
+        let mut buf = [0u8; 6];
+        let mut writer = TextWriter::new(&mut buf);
+        let _ = write!(writer, "{}", "abééé");
+        assert_eq!(writer.as_str(), "ab...");
+        assert_eq!(writer.dropped(), 6);
+
+        //The same when the "é" was already written and has to make room
+        let mut buf = [0u8; 6];
+        let mut writer = TextWriter::new(&mut buf);
+        let _ = write!(writer, "{}{}", "abé", "cdef");
+        assert_eq!(writer.as_str(), "ab...");
+        assert_eq!(writer.dropped(), 6);
+    }
+
+    #[test]
+    fn buffers_shorter_than_the_ellipsis() {
+        let mut buf = [0u8; 2];
+        let mut writer = TextWriter::new(&mut buf);
+        let _ = write!(writer, "{}", "abc");
+        assert_eq!(writer.as_str(), "..");
+        assert_eq!(writer.dropped(), 3);
+
+        let mut buf = [0u8; 0];
+        let mut writer = TextWriter::new(&mut buf);
+        let _ = write!(writer, "{}", "a");
+        assert_eq!(writer.as_str(), "");
+        assert_eq!(writer.dropped(), 1);
     }
 }
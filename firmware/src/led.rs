@@ -0,0 +1,89 @@
+//! The onboard LED on `PIN_25`, driven by a PWM slice so it can be dimmed and animated
+//! without the host timing every toggle
+
+use crate::power;
+use embassy_rp::pwm::{Config, Pwm};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use icd::LedState;
+
+/// PWM counter wrap, a level of 100% is this duty
+const PWM_TOP: u16 = 1000;
+/// One heartbeat, two flashes and then a pause
+const HEARTBEAT_MS: u64 = 1200;
+
+/// The state to switch to, set by the [`SetLedEndpoint`](icd::SetLedEndpoint) handler
+pub static LED_STATE: Signal<ThreadModeRawMutex, LedState> = Signal::new();
+
+pub struct Led {
+    pwm: Pwm<'static>,
+    config: Config,
+}
+
+impl Led {
+    pub fn new(mut pwm: Pwm<'static>) -> Self {
+        let mut config = Config::default();
+        config.top = PWM_TOP;
+        config.compare_b = 0;
+        pwm.set_config(&config);
+        Led { pwm, config }
+    }
+
+    /// Sets the brightness from 0 to 100. Squared so it looks roughly linear to the eye
+    pub fn set_level(&mut self, percent: u8) {
+        let percent = percent.min(100) as u16;
+        self.config.compare_b = percent * percent * PWM_TOP / 10_000;
+        self.pwm.set_config(&self.config);
+    }
+}
+
+/// Runs the LED patterns, updating the brightness every 10ms. The LED stays off while USB is
+/// suspended
+#[embassy_executor::task]
+pub async fn led_task(mut led: Led) {
+    let mut state = LedState::Off;
+    let mut ticker = Ticker::every(Duration::from_millis(10));
+    loop {
+        if let Some(new_state) = LED_STATE.try_take() {
+            state = new_state;
+        }
+        if power::is_suspended() {
+            led.set_level(0);
+            Timer::after(power::SUSPENDED_POLL).await;
+            ticker.reset();
+            continue;
+        }
+        led.set_level(level_for(&state, Instant::now()));
+        ticker.next().await;
+    }
+}
+
+fn level_for(state: &LedState, now: Instant) -> u8 {
+    let ms = now.as_millis();
+    match *state {
+        LedState::Off => 0,
+        LedState::On => 100,
+        LedState::Brightness(level) => level,
+        LedState::Blink { on_ms, off_ms } => {
+            let period = (on_ms as u64 + off_ms as u64).max(1);
+            if ms % period < on_ms as u64 {
+                100
+            } else {
+                0
+            }
+        }
+        LedState::Heartbeat => match ms % HEARTBEAT_MS {
+            0..100 | 200..300 => 100,
+            _ => 0,
+        },
+        LedState::Breathing { period_ms } => triangle_wave(period_ms, now),
+    }
+}
+
+/// Goes from 0 up to 100 and back down to 0 once every `period_ms`
+pub fn triangle_wave(period_ms: u16, now: Instant) -> u8 {
+    let period = period_ms.max(1) as u64;
+    let phase = (now.as_millis() % period) * 200 / period;
+    let level = if phase > 100 { 200 - phase } else { phase };
+    level as u8
+}
@@ -0,0 +1,105 @@
+//! Logging that reaches the host, not just a debug probe.
+//!
+//! The [`error!`], [`warn!`], [`info!`] and [`debug!`] macros format a [`LogRecord`], mirror it to
+//! defmt and queue it. [`logging_task`] publishes the queue on the [`LogTopic`]. Records below the
+//! level set through [`SetLogLevelEndpoint`](icd::SetLogLevelEndpoint) are dropped right away.
+
+use crate::{app::AppTx, display::SharedRawMutex};
+use core::fmt::{Arguments, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+use embassy_futures::select::{select, Either};
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Ticker};
+use heapless::String;
+use icd::{LogLevel, LogRecord, LogTopic};
+use postcard_rpc::{header::VarSeq, server::Sender};
+
+/// Records waiting to be sent. If the host is slow, new records are dropped.
+/// The render task logs too, which may run on the other core
+static RECORDS: Channel<SharedRawMutex, LogRecord, 8> = Channel::new();
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+const LEVELS: [LogLevel; 4] = [
+    LogLevel::Error,
+    LogLevel::Warn,
+    LogLevel::Info,
+    LogLevel::Debug,
+];
+
+pub fn set_level(level: LogLevel) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> LogLevel {
+    LEVELS[MAX_LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+/// Used by the logging macros, use those instead
+pub fn log(level: LogLevel, module: &str, args: Arguments<'_>) {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut record = LogRecord {
+        level,
+        module: String::new(),
+        message: String::new(),
+    };
+    //Anything that does not fit is cut off
+    let _ = record
+        .module
+        .push_str(&module[..module.len().min(record.module.capacity())]);
+    let _ = record.message.write_fmt(args);
+
+    match level {
+        LogLevel::Error => defmt::error!("{}: {}", module, record.message.as_str()),
+        LogLevel::Warn => defmt::warn!("{}: {}", module, record.message.as_str()),
+        LogLevel::Info => defmt::info!("{}: {}", module, record.message.as_str()),
+        LogLevel::Debug => defmt::debug!("{}: {}", module, record.message.as_str()),
+    }
+    let _ = RECORDS.try_send(record);
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::logging::log(icd::LogLevel::Error, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::logging::log(icd::LogLevel::Warn, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::logging::log(icd::LogLevel::Info, module_path!(), format_args!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::logging::log(icd::LogLevel::Debug, module_path!(), format_args!($($arg)*))
+    };
+}
+
+#[allow(unused_imports)]
+pub(crate) use {debug, error, info, warn};
+
+/// Sends queued records to the host, with an uptime "sign of life" every few seconds
+#[embassy_executor::task]
+pub async fn logging_task(sender: Sender<AppTx>) {
+    let mut ticker = Ticker::every(Duration::from_secs(3));
+    let start = Instant::now();
+    let mut seq: u16 = 0;
+    loop {
+        match select(RECORDS.receive(), ticker.next()).await {
+            Either::First(record) => {
+                let _ = sender.publish::<LogTopic>(VarSeq::Seq2(seq), &record).await;
+                seq = seq.wrapping_add(1);
+            }
+            Either::Second(()) => debug!("Uptime: {:?}", start.elapsed()),
+        }
+    }
+}
@@ -1,44 +1,79 @@
 #![no_std]
 #![no_main]
-use core::sync::atomic::{compiler_fence, Ordering};
+use core::cell::RefCell;
 
-use app::AppTx;
+use buzzer::Buzzer;
+use display::{I2c1Bus, Panel, Ui, UiMutex};
+#[cfg(not(any(feature = "spi-display", feature = "epaper")))]
 use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
 use embassy_executor::Spawner;
 use embassy_rp::{
     bind_interrupts,
     block::ImageDef,
-    gpio::{Level, Output},
+    flash::{Blocking, Flash},
+    gpio::{Input, Pull},
     i2c::{self, I2c},
-    peripherals::{I2C1, USB},
+    peripherals::{I2C1, PIO0, USB},
+    pio::{self, Pio},
+    pio_programs::{
+        rotary_encoder::{PioEncoder, PioEncoderProgram},
+        ws2812::{PioWs2812, PioWs2812Program},
+    },
+    pwm::Pwm,
     usb,
+    watchdog::Watchdog,
 };
-use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Instant, Ticker};
-use embassy_usb::{Config, UsbDevice};
-use embedded_graphics::{image::Image, pixelcolor::BinaryColor, prelude::Point, prelude::*};
-use postcard_rpc::{
-    sender_fmt,
-    server::{Dispatch, Sender, Server},
+#[cfg(any(feature = "spi-display", feature = "epaper"))]
+use embassy_rp::{
+    gpio::{Level, Output},
+    spi::Spi,
 };
+use embassy_sync::{blocking_mutex, mutex::Mutex};
+use embassy_usb::{Config, UsbDevice};
+use icd::{LedState, RgbEffect};
+use led::Led;
+use ota::Ota;
+use postcard_rpc::server::{Dispatch, Server};
+use settings::{SettingsMutex, SettingsStore, SharedFlash, FLASH_SIZE};
+#[cfg(not(any(feature = "spi-display", feature = "epaper")))]
 use ssd1306::{
-    prelude::DisplayRotation, prelude::*, size::DisplaySize128x64, I2CDisplayInterface,
-    Ssd1306Async,
+    prelude::DisplayRotation, size::DisplaySize128x64, I2CDisplayInterface, Ssd1306Async,
 };
 use static_cell::StaticCell;
-use tinybmp::Bmp;
-type I2c1Bus = Mutex<NoopRawMutex, I2c<'static, I2C1, i2c::Async>>;
 
 bind_interrupts!(pub struct Irqs {
     USBCTRL_IRQ => usb::InterruptHandler<USB>;
     I2C1_IRQ => i2c::InterruptHandler<I2C1>;
+    PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
 });
 
-use {defmt_rtt as _, panic_probe as _};
+use defmt_rtt as _;
 
 pub mod app;
+pub mod buzzer;
+pub mod clock;
+pub mod display;
+#[cfg(feature = "epaper")]
+pub mod epaper;
 pub mod handlers;
 pub mod io;
+pub mod led;
+pub mod logging;
+pub mod menu;
+pub mod notify;
+#[cfg(not(any(feature = "spi-display", feature = "epaper")))]
+pub mod oled;
+pub mod ota;
+pub mod panic;
+pub mod power;
+pub mod qr;
+pub mod rgb;
+pub mod settings;
+#[cfg(feature = "spi-display")]
+pub mod tft;
+pub mod theme;
+pub mod transport;
+pub mod usb_identity;
 
 #[link_section = ".start_block"]
 #[used]
@@ -48,17 +83,22 @@ pub static IMAGE_DEF: ImageDef = ImageDef::secure_exe();
 // This is needed if you are using picotool to flash the device
 #[link_section = ".bi_entries"]
 #[used]
-pub static PICOTOOL_ENTRIES: [embassy_rp::binary_info::EntryAddr; 4] = [
+pub static PICOTOOL_ENTRIES: [embassy_rp::binary_info::EntryAddr; 7] = [
     embassy_rp::binary_info::rp_program_name!(c"pc-usage-monitor"),
     embassy_rp::binary_info::rp_program_description!(c"Display your computer's usage on a Pico"),
     embassy_rp::binary_info::rp_cargo_version!(),
     embassy_rp::binary_info::rp_program_build_attribute!(),
+    // The USB identity this was built with, listed as features by `picotool info`
+    embassy_rp::binary_info::rp_program_feature!(usb_identity::USB_ID_FEATURE),
+    embassy_rp::binary_info::rp_program_feature!(usb_identity::USB_MANUFACTURER_FEATURE),
+    embassy_rp::binary_info::rp_program_feature!(usb_identity::USB_PRODUCT_FEATURE),
 ];
 
 fn usb_config(serial: &'static str) -> Config<'static> {
-    let mut config = Config::new(0x16c0, 0x27DD);
-    config.manufacturer = Some("Bailey Townsend");
-    config.product = Some("pc-usage-monitor");
+    //These can be changed at build time, see build.rs
+    let mut config = Config::new(usb_identity::USB_VID, usb_identity::USB_PID);
+    config.manufacturer = Some(usb_identity::USB_MANUFACTURER);
+    config.product = Some(usb_identity::USB_PRODUCT);
     config.serial_number = Some(serial);
 
     // Required for windows compatibility.
@@ -75,6 +115,10 @@ fn usb_config(serial: &'static str) -> Config<'static> {
 async fn main(spawner: Spawner) {
     // SYSTEM INIT
     let p = embassy_rp::init(Default::default());
+    let last_panic = panic::take_previous();
+    if let Some(report) = &last_panic {
+        logging::error!("Reset by a panic: {}", report.message.as_str());
+    }
 
     //This generates a unique serial number for the device
     let unique_id: u64 = embassy_rp::otp::get_chipid().unwrap();
@@ -104,42 +148,111 @@ async fn main(spawner: Spawner) {
     let pbufs = app::PBUFS.take();
     let config = usb_config(ser_buf);
 
-    //Set up the LED
-    let mut led = Output::new(p.PIN_25, Level::Low);
+    //Set up the LED, PIN_25 is channel B of PWM slice 4
+    let led = Led::new(Pwm::new_output_b(
+        p.PWM_SLICE4,
+        p.PIN_25,
+        embassy_rp::pwm::Config::default(),
+    ));
+
+    //Piezo buzzer on PIN_15, channel B of PWM slice 7. Nothing happens if none is connected
+    let buzzer = Buzzer::new(Pwm::new_output_b(
+        p.PWM_SLICE7,
+        p.PIN_15,
+        embassy_rp::pwm::Config::default(),
+    ));
+
+    //Load the saved settings from the end of flash
+    let flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(p.FLASH);
+    static FLASH: StaticCell<SharedFlash> = StaticCell::new();
+    let flash: &'static SharedFlash = FLASH.init(blocking_mutex::Mutex::new(RefCell::new(flash)));
+    let settings_store = SettingsStore::load(flash);
+    let initial_settings = settings_store.get().clone();
+    static SETTINGS: StaticCell<SettingsMutex> = StaticCell::new();
+    let settings: &'static SettingsMutex = SETTINGS.init(Mutex::new(settings_store));
+
+    //Rotary encoder on pins 2 and 3 with its push switch on pin 4, used for the settings menu
+    let Pio {
+        mut common,
+        sm0,
+        sm1,
+        ..
+    } = Pio::new(p.PIO0, Irqs);
+    let encoder_program = PioEncoderProgram::new(&mut common);
+    let encoder = PioEncoder::new(&mut common, sm0, p.PIN_2, p.PIN_3, &encoder_program);
+    let button = Input::new(p.PIN_4, Pull::Up);
+
+    //WS2812 status LED (or a short strip) with its data line on pin 16
+    let ws2812_program = PioWs2812Program::new(&mut common);
+    let rgb_led = PioWs2812::new(&mut common, sm1, p.DMA_CH0, p.PIN_16, &ws2812_program);
 
-    //Setup the I2c bus to connect to the SSD1306 display
+    //Setup the I2c bus to connect to the SSD1306 display. The self-test scans it with a color
+    //display as well
     let i2c = I2c::new_async(p.I2C1, p.PIN_27, p.PIN_26, Irqs, i2c::Config::default());
     static I2C_BUS: StaticCell<I2c1Bus> = StaticCell::new();
-    let i2c_bus = I2C_BUS.init(Mutex::new(i2c));
+    let i2c_bus: &'static I2c1Bus = I2C_BUS.init(Mutex::new(i2c));
 
     // Set up for the SSD1206 display
-    let i2c_dev = I2cDevice::new(i2c_bus);
-    let interface = I2CDisplayInterface::new(i2c_dev);
-    let mut display = Ssd1306Async::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
-        .into_buffered_graphics_mode();
-    let display_init_result = display.init().await;
-    //If the display doesn't init since we do not have logging yet we will turn on the onboard LED
-    if display_init_result.is_err() {
-        led.set_high();
-        loop {
-            compiler_fence(Ordering::SeqCst);
-        }
+    #[cfg(not(any(feature = "spi-display", feature = "epaper")))]
+    let mut panel = {
+        let i2c_dev = I2cDevice::new(i2c_bus);
+        let interface = I2CDisplayInterface::new(i2c_dev);
+        let display = Ssd1306Async::new(interface, DisplaySize128x64, DisplayRotation::Rotate0);
+        Panel::new(display, i2c_bus)
+    };
+    //Or a color display on SPI0: SCK on pin 18, MOSI on 19, CS on 17, DC on 20, reset on 21 and
+    //the backlight on 22, channel A of PWM slice 3
+    #[cfg(feature = "spi-display")]
+    let mut panel = {
+        let spi = Spi::new_txonly(p.SPI0, p.PIN_18, p.PIN_19, p.DMA_CH1, tft::spi_config());
+        let backlight =
+            Pwm::new_output_a(p.PWM_SLICE3, p.PIN_22, embassy_rp::pwm::Config::default());
+        Panel::new(
+            spi,
+            Output::new(p.PIN_17, Level::High),
+            Output::new(p.PIN_20, Level::High),
+            Output::new(p.PIN_21, Level::High),
+            backlight,
+        )
+    };
+    //Or an e-paper panel on the same pins, with BUSY on pin 22 instead of the backlight
+    #[cfg(feature = "epaper")]
+    let mut panel = {
+        let spi = Spi::new_txonly(p.SPI0, p.PIN_18, p.PIN_19, p.DMA_CH1, epaper::spi_config());
+        Panel::new(
+            spi,
+            Output::new(p.PIN_17, Level::High),
+            Output::new(p.PIN_20, Level::High),
+            Output::new(p.PIN_21, Level::High),
+            Input::new(p.PIN_22, Pull::None),
+        )
+    };
+    //If the display doesn't init we keep going so USB and the self-test endpoint still work,
+    //the onboard LED stays on to show something is wrong
+    let mut led_state = LedState::Off;
+    let ready = panel.init().await;
+    if !ready {
+        logging::error!("Display did not initialize, check its wiring or run the self-test");
+        led_state = LedState::On;
+        led::LED_STATE.signal(led_state);
     }
-
-    // Displays the boot screen
-    let bmp_logo_data = include_bytes!("../pictures/logo-poststation.bmp");
-    let bmp_logo = Bmp::<BinaryColor>::from_slice(bmp_logo_data).unwrap();
-    let _ = Image::new(&bmp_logo, Point::new(0, 0)).draw(&mut display);
-    display.flush().await.unwrap();
+    static UI: StaticCell<UiMutex> = StaticCell::new();
+    let ui: &'static UiMutex = UI.init(Mutex::new(Ui::new(initial_settings)));
 
     let context = app::Context {
         unique_id,
-        led,
-        display,
+        led_state,
+        rgb_effect: RgbEffect::Status,
+        alerting: false,
+        last_panic,
+        ui,
+        settings,
+        i2c_bus,
+        ota: Ota::new(flash),
     };
 
-    let (device, tx_impl, rx_impl) =
-        app::STORAGE.init_poststation(driver, config, pbufs.tx_buf.as_mut_slice());
+    // Poststation's USB interface and a serial port for hosts without poststation
+    let (device, tx_impl, rx_impl) = transport::init(driver, config, pbufs.tx_buf.as_mut_slice());
     let dispatcher = app::MyApp::new(context, spawner.into());
 
     let vkk = dispatcher.min_key_len();
@@ -155,10 +268,22 @@ async fn main(spawner: Spawner) {
     // We need to spawn the USB task so that USB messages are handled by
     // embassy-usb
     spawner.must_spawn(usb_task(device));
-    spawner.must_spawn(logging_task(sender));
+    #[cfg(not(feature = "core1-render"))]
+    spawner.must_spawn(display::render_task(ui, panel, ready));
+    #[cfg(feature = "core1-render")]
+    display::spawn_on_core1(p.CORE1, ui, panel, ready);
+    spawner.must_spawn(power::power_task(ui));
+    spawner.must_spawn(clock::clock_task(ui));
+    spawner.must_spawn(notify::notification_task(ui));
+    spawner.must_spawn(qr::qr_task(ui));
+    spawner.must_spawn(ota::watchdog_task(Watchdog::new(p.WATCHDOG)));
+    spawner.must_spawn(logging::logging_task(sender.clone()));
+    spawner.must_spawn(led::led_task(led));
+    spawner.must_spawn(rgb::rgb_task(rgb_led));
+    spawner.must_spawn(buzzer::buzzer_task(buzzer));
+    spawner.must_spawn(menu::input_task(encoder, button, ui, settings, sender));
     // spawner.must_spawn(boot_screen(i2c_bus));
 
-    let mut error_displaying = false;
     // Begin running!
     loop {
         // If the host disconnects, we'll return an error here.
@@ -166,18 +291,8 @@ async fn main(spawner: Spawner) {
 
         let _ = server.run().await;
 
-        if !error_displaying {
-            //If theres an error we only want it to show the first time. If it connects then this gets cleared on first display endpoint call
-            let i2c_dev = I2cDevice::new(i2c_bus);
-            let interface = I2CDisplayInterface::new(i2c_dev);
-            let mut display =
-                Ssd1306Async::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
-                    .into_terminal_mode();
-            let _ = display.clear().await;
-            let _ = display.write_str("\nCannot connect :(").await;
-
-            error_displaying = true;
-        }
+        //Stays up until the host sends stats again
+        ui.lock().await.set_disconnected();
     }
 }
 
@@ -186,14 +301,3 @@ async fn main(spawner: Spawner) {
 pub async fn usb_task(mut usb: UsbDevice<'static, app::AppDriver>) {
     usb.run().await;
 }
-
-/// This task is a "sign of life" logger
-#[embassy_executor::task]
-pub async fn logging_task(sender: Sender<AppTx>) {
-    let mut ticker = Ticker::every(Duration::from_secs(3));
-    let start = Instant::now();
-    loop {
-        ticker.next().await;
-        let _ = sender_fmt!(sender, "Uptime: {:?}", start.elapsed()).await;
-    }
-}
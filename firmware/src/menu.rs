@@ -0,0 +1,339 @@
+//! The on-device settings menu, driven by a rotary encoder with a push switch.
+//!
+//! Outside of the menu turning the knob switches pages and pressing it opens the menu.
+//! In the menu turning moves between items, pressing starts (and stops) editing the selected item.
+//! Leaving through "Exit" saves the settings to flash and reports them to the host.
+
+use crate::{
+    app::AppTx,
+    display::{self, UiMutex, SMALL_FONT},
+    io::TextWriter,
+    logging,
+    settings::SettingsMutex,
+    theme::{Canvas, Palette},
+};
+use core::fmt::Write;
+use embassy_futures::select::{select, Either};
+use embassy_rp::{
+    gpio::Input,
+    peripherals::PIO0,
+    pio_programs::rotary_encoder::{Direction, PioEncoder},
+};
+use embassy_time::Timer;
+use embedded_graphics::{
+    prelude::*,
+    text::{Baseline, Text},
+};
+use icd::{
+    Brightness, DeviceSettings, I2cSpeed, Layout, MultiHostLayout, Page, Rotation,
+    SettingsChangedTopic,
+};
+use postcard_rpc::{header::VarSeq, server::Sender};
+
+/// Every order the three pages can be shown in, the menu steps through these
+const PAGE_ORDERS: [[Page; 3]; 6] = [
+    [Page::Overview, Page::Cpu, Page::Memory],
+    [Page::Overview, Page::Memory, Page::Cpu],
+    [Page::Cpu, Page::Overview, Page::Memory],
+    [Page::Cpu, Page::Memory, Page::Overview],
+    [Page::Memory, Page::Overview, Page::Cpu],
+    [Page::Memory, Page::Cpu, Page::Overview],
+];
+
+const LAYOUTS: [Layout; 3] = [Layout::Pages, Layout::LargeDigits, Layout::Gauges];
+
+const I2C_SPEEDS: [I2cSpeed; 3] = [I2cSpeed::Standard, I2cSpeed::Fast, I2cSpeed::FastPlus];
+
+const BRIGHTNESS_LEVELS: [Brightness; 5] = [
+    Brightness::Dimmest,
+    Brightness::Dim,
+    Brightness::Normal,
+    Brightness::Brighter,
+    Brightness::Brightest,
+];
+
+/// How much a single click of the encoder moves an alert threshold
+const ALERT_STEP: i16 = 5;
+
+#[derive(Clone, Copy, PartialEq)]
+enum MenuItem {
+    Brightness,
+    Rotation,
+    PageOrder,
+    Layout,
+    CpuAlert,
+    MemoryAlert,
+    Buzzer,
+    Clock,
+    MultiHost,
+    I2cSpeed,
+    Exit,
+}
+
+const MENU_ITEMS: [MenuItem; 11] = [
+    MenuItem::Brightness,
+    MenuItem::Rotation,
+    MenuItem::Layout,
+    MenuItem::PageOrder,
+    MenuItem::Clock,
+    MenuItem::MultiHost,
+    MenuItem::CpuAlert,
+    MenuItem::MemoryAlert,
+    MenuItem::Buzzer,
+    MenuItem::I2cSpeed,
+    MenuItem::Exit,
+];
+/// How many items fit on the screen at once with the small font
+const VISIBLE_ITEMS: usize = 6;
+
+/// A single user action on the encoder
+#[derive(Clone, Copy)]
+pub enum Control {
+    /// Clicks turned, positive is clockwise
+    Turn(i8),
+    Press,
+}
+
+/// State of the open menu. `draft` holds the edited settings until the menu is left
+pub struct Menu {
+    selected: usize,
+    editing: bool,
+    draft: DeviceSettings,
+}
+
+impl Menu {
+    pub fn new(settings: DeviceSettings) -> Self {
+        Menu {
+            selected: 0,
+            editing: false,
+            draft: settings,
+        }
+    }
+
+    /// Takes over settings changed while the menu is open, so leaving it does not undo them.
+    /// Edits not saved yet are dropped
+    pub fn refresh(&mut self, settings: &DeviceSettings) {
+        self.draft = settings.clone();
+    }
+
+    pub fn draw<D: Canvas>(&self, display: &mut D, palette: &Palette<D::Color>) {
+        //Scroll so the selected item is always on screen
+        let first = self.selected.saturating_sub(VISIBLE_ITEMS - 1);
+        let shown = MENU_ITEMS
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(VISIBLE_ITEMS);
+        for (row, (index, item)) in shown.enumerate() {
+            let buffer = &mut [0u8; 32];
+            let mut writer = TextWriter::new(buffer);
+            let marker = match (index == self.selected, self.editing) {
+                (true, true) => '*',
+                (true, false) => '>',
+                _ => ' ',
+            };
+            let _ = write!(&mut writer, "{}", marker);
+            let _ = self.write_item(&mut writer, *item);
+            let _ = Text::with_baseline(
+                writer.as_str(),
+                Point::new(0, row as i32 * 10),
+                palette.text(SMALL_FONT),
+                Baseline::Top,
+            )
+            .draw(display);
+        }
+    }
+
+    fn write_item(&self, out: &mut TextWriter, item: MenuItem) -> core::fmt::Result {
+        let draft = &self.draft;
+        match item {
+            MenuItem::Brightness => write!(out, "Brightness {:?}", draft.brightness),
+            MenuItem::Rotation => write!(out, "Rotation {:?}", draft.rotation),
+            MenuItem::PageOrder => {
+                write!(out, "Pages")?;
+                for page in draft.page_order {
+                    let short = match page {
+                        Page::Overview => "All",
+                        Page::Cpu => "CPU",
+                        Page::Memory => "Ram",
+                        Page::Clock => "Time",
+                    };
+                    write!(out, " {}", short)?;
+                }
+                Ok(())
+            }
+            MenuItem::Layout => match draft.layout {
+                Layout::Pages => write!(out, "Layout pages"),
+                Layout::LargeDigits => write!(out, "Layout big digits"),
+                Layout::Gauges => write!(out, "Layout gauges"),
+            },
+            MenuItem::CpuAlert => write_threshold(out, "CPU alert", draft.cpu_alert),
+            MenuItem::MemoryAlert => write_threshold(out, "Ram alert", draft.memory_alert),
+            MenuItem::Buzzer => match draft.buzzer_muted {
+                true => write!(out, "Buzzer muted"),
+                false => write!(out, "Buzzer on"),
+            },
+            MenuItem::Clock => match draft.show_clock {
+                true => write!(out, "Clock page on"),
+                false => write!(out, "Clock page off"),
+            },
+            MenuItem::MultiHost => match draft.multi_host {
+                MultiHostLayout::Rotate => write!(out, "Hosts rotate"),
+                MultiHostLayout::SideBySide => write!(out, "Hosts side by side"),
+            },
+            MenuItem::I2cSpeed => match draft.i2c_speed {
+                I2cSpeed::Standard => write!(out, "I2C 100kHz"),
+                I2cSpeed::Fast => write!(out, "I2C 400kHz"),
+                I2cSpeed::FastPlus => write!(out, "I2C 1MHz"),
+            },
+            MenuItem::Exit => write!(out, "Exit"),
+        }
+    }
+
+    fn adjust(&mut self, steps: i8) {
+        let draft = &mut self.draft;
+        match MENU_ITEMS[self.selected] {
+            MenuItem::Brightness => {
+                let current = BRIGHTNESS_LEVELS
+                    .iter()
+                    .position(|b| *b == draft.brightness)
+                    .unwrap_or(2) as i8;
+                let next = (current + steps).clamp(0, BRIGHTNESS_LEVELS.len() as i8 - 1);
+                draft.brightness = BRIGHTNESS_LEVELS[next as usize];
+            }
+            MenuItem::Rotation => {
+                if steps % 2 != 0 {
+                    draft.rotation = match draft.rotation {
+                        Rotation::Normal => Rotation::Flipped,
+                        Rotation::Flipped => Rotation::Normal,
+                    };
+                }
+            }
+            MenuItem::PageOrder => {
+                let current = PAGE_ORDERS
+                    .iter()
+                    .position(|order| *order == draft.page_order)
+                    .unwrap_or(0) as i8;
+                let next = (current + steps).rem_euclid(PAGE_ORDERS.len() as i8);
+                draft.page_order = PAGE_ORDERS[next as usize];
+            }
+            MenuItem::Layout => {
+                let current = LAYOUTS.iter().position(|l| *l == draft.layout).unwrap_or(0) as i8;
+                let next = (current + steps).rem_euclid(LAYOUTS.len() as i8);
+                draft.layout = LAYOUTS[next as usize];
+            }
+            MenuItem::CpuAlert => draft.cpu_alert = step_threshold(draft.cpu_alert, steps),
+            MenuItem::MemoryAlert => draft.memory_alert = step_threshold(draft.memory_alert, steps),
+            MenuItem::Buzzer => {
+                if steps % 2 != 0 {
+                    draft.buzzer_muted = !draft.buzzer_muted;
+                }
+            }
+            MenuItem::Clock => {
+                if steps % 2 != 0 {
+                    draft.show_clock = !draft.show_clock;
+                }
+            }
+            MenuItem::MultiHost => {
+                if steps % 2 != 0 {
+                    draft.multi_host = match draft.multi_host {
+                        MultiHostLayout::Rotate => MultiHostLayout::SideBySide,
+                        MultiHostLayout::SideBySide => MultiHostLayout::Rotate,
+                    };
+                }
+            }
+            MenuItem::I2cSpeed => {
+                let current = I2C_SPEEDS
+                    .iter()
+                    .position(|s| *s == draft.i2c_speed)
+                    .unwrap_or(0) as i8;
+                let next = (current + steps).clamp(0, I2C_SPEEDS.len() as i8 - 1);
+                draft.i2c_speed = I2C_SPEEDS[next as usize];
+            }
+            MenuItem::Exit => {}
+        }
+    }
+}
+
+fn write_threshold(out: &mut TextWriter, label: &str, threshold: u8) -> core::fmt::Result {
+    match threshold {
+        0 => write!(out, "{} off", label),
+        t => write!(out, "{} {}%", label, t),
+    }
+}
+
+fn step_threshold(threshold: u8, steps: i8) -> u8 {
+    (threshold as i16 + steps as i16 * ALERT_STEP).clamp(0, 100) as u8
+}
+
+/// Reads the encoder and its switch and drives the pages and menu
+#[embassy_executor::task]
+pub async fn input_task(
+    mut encoder: PioEncoder<'static, PIO0, 0>,
+    mut button: Input<'static>,
+    ui: &'static UiMutex,
+    settings: &'static SettingsMutex,
+    sender: Sender<AppTx>,
+) {
+    let mut seq: u16 = 0;
+    loop {
+        let control = match select(encoder.read(), button.wait_for_falling_edge()).await {
+            Either::First(Direction::Clockwise) => Control::Turn(1),
+            Either::First(Direction::CounterClockwise) => Control::Turn(-1),
+            Either::Second(()) => {
+                //Simple debounce, the press has to still be there after the contacts settle
+                Timer::after_millis(20).await;
+                if button.is_high() {
+                    continue;
+                }
+                Control::Press
+            }
+        };
+
+        if let Some(saved) = handle_control(ui, control).await {
+            if let Err(e) = settings.lock().await.save(saved.clone()) {
+                logging::warn!("Could not save settings: {:?}", e);
+            }
+            let _ = sender
+                .publish::<SettingsChangedTopic>(VarSeq::Seq2(seq), &saved)
+                .await;
+            seq = seq.wrapping_add(1);
+        }
+    }
+}
+
+/// Applies a single control to the ui. Returns the new settings when the menu was left with changes
+async fn handle_control(ui: &UiMutex, control: Control) -> Option<DeviceSettings> {
+    let mut ui = ui.lock().await;
+    let Some(menu) = ui.menu.as_mut() else {
+        match control {
+            Control::Turn(steps) => ui.change_page(steps),
+            Control::Press => {
+                ui.menu = Some(Menu::new(ui.settings().clone()));
+                display::request_render();
+            }
+        }
+        return None;
+    };
+
+    match control {
+        Control::Turn(steps) if menu.editing => menu.adjust(steps),
+        Control::Turn(steps) => {
+            menu.selected =
+                (menu.selected as i8 + steps).rem_euclid(MENU_ITEMS.len() as i8) as usize
+        }
+        Control::Press if MENU_ITEMS[menu.selected] == MenuItem::Exit => {
+            let draft = menu.draft.clone();
+            ui.menu = None;
+            if draft == *ui.settings() {
+                display::request_render();
+                return None;
+            }
+            ui.apply_settings(draft.clone());
+            return Some(draft);
+        }
+        Control::Press => menu.editing = !menu.editing,
+    }
+    display::request_render();
+    None
+}
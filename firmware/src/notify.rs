@@ -0,0 +1,78 @@
+//! Notifications pushed by the host, like "Build finished", queued and shown one at a time.
+//!
+//! The one on screen stays up for its `duration_ms` and is then replaced by the next in the queue,
+//! or by the normal layout once the queue is empty. What is on screen is never cut short, a more
+//! important notification goes to the front of the queue instead.
+
+use crate::display::UiMutex;
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_time::Timer;
+use heapless::Vec;
+use icd::Notification;
+
+/// Notifications waiting behind the one on screen
+const QUEUE_LEN: usize = 8;
+
+/// Raised when a notification is queued, wakes up [`notification_task`]
+static QUEUED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Waiting notifications, most important first and in arrival order within a priority
+pub struct NotificationQueue {
+    waiting: Vec<Notification, QUEUE_LEN>,
+}
+
+impl NotificationQueue {
+    pub const fn new() -> Self {
+        NotificationQueue {
+            waiting: Vec::new(),
+        }
+    }
+
+    /// Queues `notification`, pushing out the least important one waiting if the queue is full
+    /// and that one is less important. Returns false if it was dropped instead
+    pub fn push(&mut self, notification: Notification) -> bool {
+        if self.waiting.is_full() {
+            match self.waiting.last() {
+                Some(last) if last.priority < notification.priority => {
+                    self.waiting.pop();
+                }
+                _ => return false,
+            }
+        }
+        let index = self
+            .waiting
+            .iter()
+            .position(|waiting| waiting.priority < notification.priority)
+            .unwrap_or(self.waiting.len());
+        let accepted = self.waiting.insert(index, notification).is_ok();
+        QUEUED.signal(());
+        accepted
+    }
+
+    pub fn pop(&mut self) -> Option<Notification> {
+        if self.waiting.is_empty() {
+            None
+        } else {
+            Some(self.waiting.remove(0))
+        }
+    }
+}
+
+/// Puts the next notification on screen whenever the current one is done
+#[embassy_executor::task]
+pub async fn notification_task(ui: &'static UiMutex) {
+    loop {
+        //The guard has to be gone before waiting, otherwise nothing else gets at the ui
+        let next = ui.lock().await.show_next_notification();
+        match next {
+            Some(until) => {
+                //Something queued meanwhile only has to wait, the timer still ends this one
+                if let Either::First(()) = select(Timer::at(until), QUEUED.wait()).await {
+                    ui.lock().await.end_notification();
+                }
+            }
+            None => QUEUED.wait().await,
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! The SSD1306 on I2C, the display the firmware was built around.
+//!
+//! Frames are drawn into a [`Frame`] laid out like the panel's memory, and [`Panel::flush`] only
+//! sends the columns of each 8-row page that changed since the last one.
+
+use crate::display::{changed_columns, I2c1Bus, SharedRawMutex};
+use core::convert::Infallible;
+use embassy_embedded_hal::{shared_bus::asynch::i2c::I2cDevice, SetConfig};
+use embassy_rp::i2c::{self, I2c};
+use embassy_rp::peripherals::I2C1;
+use embassy_time::Instant;
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+use icd::{Brightness, DeviceSettings, I2cSpeed, Rotation};
+use ssd1306::{mode::BasicMode, prelude::*, size::DisplaySize128x64};
+use ssd1306::{prelude::Brightness as PanelBrightness, Ssd1306Async};
+
+pub type Display = Ssd1306Async<
+    I2CInterface<I2cDevice<'static, SharedRawMutex, I2c<'static, I2C1, i2c::Async>>>,
+    DisplaySize128x64,
+    BasicMode,
+>;
+
+/// Width of the panel, and the bytes in one of its pages
+const WIDTH: usize = 128;
+/// The panel's memory is split into pages of 8 rows, a byte per column with the top row in bit 0
+const PAGES: usize = 8;
+
+/// Pixels laid out like the panel's memory, so changed columns can be sent as they are
+#[derive(Clone)]
+pub struct Frame {
+    pixels: [u8; WIDTH * PAGES],
+}
+
+impl Frame {
+    const fn new() -> Self {
+        Frame {
+            pixels: [0; WIDTH * PAGES],
+        }
+    }
+}
+
+impl OriginDimensions for Frame {
+    fn size(&self) -> Size {
+        Size::new(WIDTH as u32, PAGES as u32 * 8)
+    }
+}
+
+impl DrawTarget for Frame {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<BinaryColor>>,
+    {
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (usize::try_from(point.x), usize::try_from(point.y)) else {
+                continue;
+            };
+            if x >= WIDTH || y >= PAGES * 8 {
+                continue;
+            }
+            let byte = &mut self.pixels[y / 8 * WIDTH + x];
+            match color {
+                BinaryColor::On => *byte |= 1 << (y % 8),
+                BinaryColor::Off => *byte &= !(1 << (y % 8)),
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: BinaryColor) -> Result<(), Self::Error> {
+        self.pixels.fill(match color {
+            BinaryColor::On => 0xFF,
+            BinaryColor::Off => 0,
+        });
+        Ok(())
+    }
+}
+
+/// Owns the SSD1306. Only the render task uses it, so nothing else ever waits on I2C
+pub struct Panel {
+    display: Display,
+    /// For the bus speed, the panel itself only has an [`I2cDevice`]
+    bus: &'static I2c1Bus,
+    /// Drawn into by the ui
+    frame: Frame,
+    /// What the panel is showing, if `in_sync`
+    shown: Frame,
+    /// False until the first full frame after an init, the panel's memory is unknown till then
+    in_sync: bool,
+    /// The panel is switched off while USB is suspended
+    on: bool,
+    /// What was last sent to the panel and bus, `None` after an init since that resets them
+    configured: Option<(Brightness, Rotation, I2cSpeed)>,
+}
+
+impl Panel {
+    pub fn new(display: Display, bus: &'static I2c1Bus) -> Self {
+        Panel {
+            display,
+            bus,
+            frame: Frame::new(),
+            shown: Frame::new(),
+            in_sync: false,
+            on: true,
+            configured: None,
+        }
+    }
+
+    /// Sets up the panel, returns false if it does not respond
+    pub async fn init(&mut self) -> bool {
+        if self.display.init().await.is_err() {
+            return false;
+        }
+        self.in_sync = false;
+        self.on = true;
+        self.configured = None;
+        true
+    }
+
+    pub fn frame(&mut self) -> &mut Frame {
+        &mut self.frame
+    }
+
+    /// Frames go out as fast as they are drawn
+    pub fn next_refresh(&self) -> Option<Instant> {
+        None
+    }
+
+    pub async fn set_on(&mut self, on: bool) -> Result<(), ()> {
+        if self.on != on {
+            self.display.set_display_on(on).await.map_err(drop)?;
+            self.on = on;
+        }
+        Ok(())
+    }
+
+    /// Sends brightness, rotation and the bus speed if they changed
+    pub async fn configure(&mut self, settings: &DeviceSettings) -> Result<(), ()> {
+        let wanted = (settings.brightness, settings.rotation, settings.i2c_speed);
+        if self.configured == Some(wanted) {
+            return Ok(());
+        }
+
+        let mut bus_config = i2c::Config::default();
+        bus_config.frequency = match settings.i2c_speed {
+            I2cSpeed::Standard => 100_000,
+            I2cSpeed::Fast => 400_000,
+            I2cSpeed::FastPlus => 1_000_000,
+        };
+        self.bus
+            .lock()
+            .await
+            .set_config(&bus_config)
+            .map_err(drop)?;
+
+        let brightness = match settings.brightness {
+            Brightness::Dimmest => PanelBrightness::DIMMEST,
+            Brightness::Dim => PanelBrightness::DIM,
+            Brightness::Normal => PanelBrightness::NORMAL,
+            Brightness::Brighter => PanelBrightness::BRIGHTER,
+            Brightness::Brightest => PanelBrightness::BRIGHTEST,
+        };
+        let rotation = match settings.rotation {
+            Rotation::Normal => DisplayRotation::Rotate0,
+            Rotation::Flipped => DisplayRotation::Rotate180,
+        };
+        self.display
+            .set_brightness(brightness)
+            .await
+            .map_err(drop)?;
+        self.display.set_rotation(rotation).await.map_err(drop)?;
+        self.configured = Some(wanted);
+        Ok(())
+    }
+
+    /// Sends the columns of each page that differ from what the panel shows, or everything if
+    /// that is not known. Returns how many bytes of pixels went out
+    pub async fn flush(&mut self) -> Result<usize, ()> {
+        let mut sent = 0;
+        for page in 0..PAGES {
+            let row = page * WIDTH..(page + 1) * WIDTH;
+            let new = &self.frame.pixels[row.clone()];
+            let columns = match self.in_sync {
+                true => match changed_columns(new, &self.shown.pixels[row]) {
+                    Some(columns) => columns,
+                    None => continue,
+                },
+                false => 0..WIDTH,
+            };
+            let top = (page * 8) as u8;
+            self.display
+                .set_draw_area((columns.start as u8, top), (columns.end as u8, top + 8))
+                .await
+                .map_err(drop)?;
+            self.display
+                .draw(&new[columns.clone()])
+                .await
+                .map_err(drop)?;
+            sent += columns.len();
+        }
+        self.shown.clone_from(&self.frame);
+        self.in_sync = true;
+        Ok(sent)
+    }
+}
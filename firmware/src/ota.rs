@@ -0,0 +1,208 @@
+//! Firmware updates over postcard-rpc, using the A/B partitions of the embassy-boot bootloader.
+//!
+//! The host sends the image in order with [`Ota::write`], it is buffered into flash sectors and
+//! written to the DFU partition. [`Ota::finish`] checks the CRC of what ended up in flash, marks
+//! the update and reboots, after which the bootloader swaps the partitions. The new firmware has
+//! to be confirmed by the host within [`CONFIRM_TIMEOUT`], otherwise [`watchdog_task`] resets the
+//! device and the bootloader swaps the old firmware back.
+
+use crate::logging;
+use crate::settings::{AppFlash, SharedFlash};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crc::{Crc, CRC_32_ISO_HDLC};
+use embassy_boot_rp::{BlockingFirmwareUpdater, BlockingPartition, FirmwareUpdaterConfig, State};
+use embassy_futures::select::{select, Either};
+use embassy_rp::{flash::ERASE_SIZE, watchdog::Watchdog};
+use embassy_sync::{
+    blocking_mutex::raw::{NoopRawMutex, ThreadModeRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use icd::{OtaBegin, OtaChunk, OtaError, OtaResult};
+use static_cell::StaticCell;
+
+/// Same as the bootloader, it starts the watchdog before jumping to us
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(8);
+const FEED_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a freshly swapped in firmware waits for the host before rolling back
+pub const CONFIRM_TIMEOUT: Duration = Duration::from_secs(120);
+/// Gives the finish endpoint time to send its response before resetting
+const REBOOT_DELAY: Duration = Duration::from_millis(500);
+
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+type Partition = BlockingPartition<'static, NoopRawMutex, AppFlash>;
+
+static REBOOT: Signal<ThreadModeRawMutex, ()> = Signal::new();
+static CONFIRMED: AtomicBool = AtomicBool::new(true);
+
+struct Transfer {
+    size: u32,
+    crc32: u32,
+    received: u32,
+}
+
+pub struct Ota {
+    updater: BlockingFirmwareUpdater<'static, Partition, Partition>,
+    flash: &'static SharedFlash,
+    dfu_offset: u32,
+    dfu_size: u32,
+    transfer: Option<Transfer>,
+    /// Collects chunks until a whole sector can be written
+    sector: &'static mut [u8; ERASE_SIZE],
+}
+
+impl Ota {
+    /// Sets up the updater. If we were just swapped in, the watchdog task starts waiting for the
+    /// host to confirm us
+    pub fn new(flash: &'static SharedFlash) -> Self {
+        static ALIGNED: StaticCell<[u8; 1]> = StaticCell::new();
+        static SECTOR: StaticCell<[u8; ERASE_SIZE]> = StaticCell::new();
+
+        let config = FirmwareUpdaterConfig::from_linkerfile_blocking(flash, flash);
+        let dfu_offset = config.dfu.offset();
+        let dfu_size = config.dfu.size();
+        let mut updater = BlockingFirmwareUpdater::new(config, ALIGNED.init([0; 1]));
+        if matches!(updater.get_state(), Ok(State::Swap)) {
+            CONFIRMED.store(false, Ordering::Relaxed);
+        }
+
+        Ota {
+            updater,
+            flash,
+            dfu_offset,
+            dfu_size,
+            transfer: None,
+            sector: SECTOR.init([0xFF; ERASE_SIZE]),
+        }
+    }
+
+    /// Starts a new transfer, dropping any that was in progress
+    pub fn begin(&mut self, begin: OtaBegin) -> OtaResult {
+        if begin.size > self.dfu_size {
+            return Err(OtaError::TooLarge);
+        }
+        self.transfer = Some(Transfer {
+            size: begin.size,
+            crc32: begin.crc32,
+            received: 0,
+        });
+        Ok(())
+    }
+
+    pub fn write(&mut self, chunk: OtaChunk<'_>) -> OtaResult {
+        let transfer = self.transfer.as_mut().ok_or(OtaError::NotStarted)?;
+        if chunk.offset != transfer.received {
+            return Err(OtaError::OutOfOrder {
+                expected: transfer.received,
+            });
+        }
+        if transfer.received + chunk.data.len() as u32 > transfer.size {
+            return Err(OtaError::TooLarge);
+        }
+
+        let mut data = chunk.data;
+        while !data.is_empty() {
+            let position = transfer.received as usize % ERASE_SIZE;
+            let len = data.len().min(ERASE_SIZE - position);
+            self.sector[position..position + len].copy_from_slice(&data[..len]);
+            transfer.received += len as u32;
+            data = &data[len..];
+
+            if position + len == ERASE_SIZE {
+                let sector_start = transfer.received as usize - ERASE_SIZE;
+                write_sector(&mut self.updater, self.sector, sector_start)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes what is left, checks the CRC and reboots into the bootloader to swap the images
+    pub fn finish(&mut self) -> OtaResult {
+        let transfer = self.transfer.take().ok_or(OtaError::NotStarted)?;
+        if transfer.received < transfer.size {
+            return Err(OtaError::Incomplete);
+        }
+
+        let leftover = transfer.size as usize % ERASE_SIZE;
+        if leftover != 0 {
+            self.sector[leftover..].fill(0xFF);
+            let sector_start = transfer.size as usize - leftover;
+            write_sector(&mut self.updater, self.sector, sector_start)?;
+        }
+
+        if self.written_crc(transfer.size)? != transfer.crc32 {
+            return Err(OtaError::CrcMismatch);
+        }
+        self.updater.mark_updated().map_err(|_| OtaError::Flash)?;
+        REBOOT.signal(());
+        Ok(())
+    }
+
+    /// Keeps the running firmware, so the bootloader does not roll back on the next reset
+    pub fn confirm(&mut self) {
+        if matches!(self.updater.get_state(), Ok(State::Swap)) {
+            if let Err(e) = self.updater.mark_booted() {
+                logging::error!("Could not confirm the firmware: {:?}", e);
+                return;
+            }
+            logging::info!("New firmware confirmed");
+        }
+        CONFIRMED.store(true, Ordering::Relaxed);
+    }
+
+    /// Reads the image back from the DFU partition
+    fn written_crc(&mut self, size: u32) -> Result<u32, OtaError> {
+        let mut digest = CRC.digest();
+        let mut position = 0;
+        while position < size {
+            let len = (size - position).min(ERASE_SIZE as u32) as usize;
+            let buffer = &mut self.sector[..len];
+            self.flash
+                .lock(|f| {
+                    f.borrow_mut()
+                        .blocking_read(self.dfu_offset + position, buffer)
+                })
+                .map_err(|_| OtaError::Flash)?;
+            digest.update(buffer);
+            position += len as u32;
+        }
+        Ok(digest.finalize())
+    }
+}
+
+fn write_sector(
+    updater: &mut BlockingFirmwareUpdater<'static, Partition, Partition>,
+    sector: &[u8; ERASE_SIZE],
+    offset: usize,
+) -> OtaResult {
+    updater.write_firmware(offset, sector).map_err(|e| {
+        logging::error!("Could not write the update: {:?}", e);
+        OtaError::Flash
+    })
+}
+
+/// Feeds the watchdog the bootloader started, and stops feeding it to reboot after an update or
+/// when a new firmware is not confirmed in time
+#[embassy_executor::task]
+pub async fn watchdog_task(mut watchdog: Watchdog) {
+    watchdog.start(WATCHDOG_TIMEOUT);
+    let start = Instant::now();
+    loop {
+        match select(REBOOT.wait(), Timer::after(FEED_INTERVAL)).await {
+            Either::First(()) => {
+                logging::info!("Rebooting to apply the update");
+                Timer::after(REBOOT_DELAY).await;
+                watchdog.trigger_reset();
+            }
+            Either::Second(()) => {
+                if !CONFIRMED.load(Ordering::Relaxed) && start.elapsed() > CONFIRM_TIMEOUT {
+                    logging::error!("Firmware was not confirmed, rolling back");
+                    Timer::after(REBOOT_DELAY).await;
+                    watchdog.trigger_reset();
+                }
+                watchdog.feed();
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Panic handler that keeps the message around for the host.
+//!
+//! Nobody has a debug probe attached in production, so on a panic the message is written to a
+//! RAM section that is not cleared on boot, drawn on the SSD1306 if that is the panel, and then
+//! the device reboots.
+//! After the reboot [`take_previous`] hands the message to the
+//! [`GetLastPanicEndpoint`](icd::GetLastPanicEndpoint) handler.
+
+use crate::io::TextWriter;
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::peripheral::SCB;
+#[cfg(not(any(feature = "spi-display", feature = "epaper")))]
+use embassy_rp::{
+    i2c::{self, I2c},
+    peripherals::{I2C1, PIN_26, PIN_27},
+};
+#[cfg(not(any(feature = "spi-display", feature = "epaper")))]
+use embedded_graphics::{
+    mono_font::{ascii, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Baseline, Text},
+};
+use icd::{PanicReport, PANIC_MESSAGE_LEN};
+#[cfg(not(any(feature = "spi-display", feature = "epaper")))]
+use ssd1306::{prelude::*, size::DisplaySize128x64, I2CDisplayInterface, Ssd1306};
+
+/// Marks a record written by the panic handler, anything else is leftover RAM contents
+const MAGIC: u32 = 0x9A41_C0DF;
+/// Characters per line with the 6x10 font
+#[cfg(not(any(feature = "spi-display", feature = "epaper")))]
+const LINE_CHARS: usize = 21;
+/// Roughly five seconds at the default 150MHz system clock, long enough to read the message
+#[cfg(not(any(feature = "spi-display", feature = "epaper")))]
+const SHOW_MESSAGE_CYCLES: u32 = 750_000_000;
+
+#[repr(C)]
+struct PanicRecord {
+    magic: u32,
+    len: u32,
+    /// Bytes of the message that did not fit
+    dropped: u32,
+    message: [u8; PANIC_MESSAGE_LEN],
+}
+
+#[link_section = ".uninit.PANIC_RECORD"]
+static mut PANIC_RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+
+/// Set on the first panic, so a panic while drawing the message goes straight to the reset
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Takes the message of the panic that caused the last reset, if there was one
+pub fn take_previous() -> Option<PanicReport> {
+    // SAFETY: Only called once at boot before anything could panic. Every bit pattern is a
+    // valid PanicRecord, the magic tells us if it holds an actual message.
+    let record = unsafe { (*addr_of_mut!(PANIC_RECORD)).assume_init_mut() };
+    if record.magic != MAGIC {
+        return None;
+    }
+    record.magic = 0;
+
+    let len = (record.len as usize).min(PANIC_MESSAGE_LEN);
+    let message = core::str::from_utf8(&record.message[..len]).ok()?;
+    let mut report = PanicReport {
+        message: heapless::String::new(),
+        dropped: record.dropped,
+    };
+    let _ = report.message.push_str(message);
+    Some(report)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    defmt::error!("{}", defmt::Display2Format(info));
+
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        // SAFETY: Interrupts are off and this is the only place writing the record
+        let record = unsafe { (*addr_of_mut!(PANIC_RECORD)).assume_init_mut() };
+        let mut writer = TextWriter::new(&mut record.message);
+        //Whatever did not fit is cut off and marked, the start of the message is the useful part
+        let _ = write!(&mut writer, "{}", info);
+        let (len, dropped) = (writer.as_str().len(), writer.dropped());
+        record.len = len as u32;
+        record.dropped = dropped as u32;
+        record.magic = MAGIC;
+
+        //The pins of a panel on SPI may be wired to anything, the message only goes to the host
+        #[cfg(not(any(feature = "spi-display", feature = "epaper")))]
+        {
+            show_on_display(&record.message[..len]);
+            cortex_m::asm::delay(SHOW_MESSAGE_CYCLES);
+        }
+    }
+
+    SCB::sys_reset();
+}
+
+/// Takes over the display with a fresh blocking I2C driver and writes the message on it
+#[cfg(not(any(feature = "spi-display", feature = "epaper")))]
+fn show_on_display(message: &[u8]) {
+    // SAFETY: We never return to the code that owns these, the device resets after this
+    let (i2c1, scl, sda) = unsafe { (I2C1::steal(), PIN_27::steal(), PIN_26::steal()) };
+    let i2c = I2c::new_blocking(i2c1, scl, sda, i2c::Config::default());
+    let interface = I2CDisplayInterface::new(i2c);
+    let mut display = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
+        .into_buffered_graphics_mode();
+    if display.init().is_err() {
+        return;
+    }
+
+    let style = MonoTextStyle::new(&ascii::FONT_6X10, BinaryColor::On);
+    let _ = Text::with_baseline("PANIC", Point::zero(), style, Baseline::Top).draw(&mut display);
+    //Wraps the message by hand, embedded-graphics does not
+    let text = core::str::from_utf8(message).unwrap_or("");
+    let lines = text
+        .split('\n')
+        .flat_map(|line| line.as_bytes().chunks(LINE_CHARS))
+        .take(5);
+    for (row, line) in lines.enumerate() {
+        let line = core::str::from_utf8(line).unwrap_or("?");
+        let position = Point::new(0, 10 + row as i32 * 10);
+        let _ = Text::with_baseline(line, position, style, Baseline::Top).draw(&mut display);
+    }
+    let _ = display.flush();
+}
@@ -0,0 +1,49 @@
+//! Follows the USB bus into suspend and back, so the device goes quiet while the PC sleeps.
+//!
+//! The display is switched off with its contents kept, and the LED tasks stop animating and
+//! only check back every [`SUSPENDED_POLL`]. On resume the last layout comes back as it was.
+
+use crate::{display::UiMutex, logging};
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_time::Duration;
+use embassy_usb::Handler;
+use static_cell::ConstStaticCell;
+
+/// How often the LED tasks look at whether the bus woke up again
+pub const SUSPENDED_POLL: Duration = Duration::from_millis(500);
+
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+/// The latest suspend state for [`power_task`], only the last change matters
+static CHANGED: Signal<ThreadModeRawMutex, bool> = Signal::new();
+
+pub static HANDLER: ConstStaticCell<PowerHandler> = ConstStaticCell::new(PowerHandler {});
+
+/// True while the host has the USB bus suspended
+pub fn is_suspended() -> bool {
+    SUSPENDED.load(Ordering::Relaxed)
+}
+
+/// Gets the suspend and resume events from embassy-usb
+pub struct PowerHandler {}
+
+impl Handler for PowerHandler {
+    fn suspended(&mut self, suspended: bool) {
+        SUSPENDED.store(suspended, Ordering::Relaxed);
+        CHANGED.signal(suspended);
+    }
+}
+
+/// Puts the display to sleep on suspend and wakes it on resume
+#[embassy_executor::task]
+pub async fn power_task(ui: &'static UiMutex) {
+    loop {
+        if CHANGED.wait().await {
+            logging::info!("USB suspended, turning the display off");
+            ui.lock().await.sleep();
+        } else {
+            logging::info!("USB resumed");
+            ui.lock().await.wake();
+        }
+    }
+}
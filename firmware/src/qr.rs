@@ -0,0 +1,168 @@
+//! QR codes from the host, so a URL or address on the computer can be picked up with a phone.
+//!
+//! The text is encoded once when it comes in, [`QrImage`] only keeps the modules. The code takes
+//! over the screen like a high priority notification until [`qr_task`] takes it down again.
+
+use crate::{
+    display::{UiMutex, SMALL_FONT},
+    theme::{Canvas, Palette, UiColor},
+};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_time::{Instant, Timer};
+use embedded_graphics::{
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Alignment, Baseline, Text},
+};
+use qrcodegen_no_heap::{QrCode, QrCodeEcc, Version};
+
+/// Version 6 codes are 41 modules across, which still fits 64 pixels with a caption and the
+/// full quiet zone
+const MAX_VERSION: Version = Version::new(6);
+const MAX_SIZE: usize = 41;
+/// Light modules around the code, scanners need the margin to find it. The standard asks for 4,
+/// less is only used when the code would not fit otherwise
+const QUIET_ZONE: i32 = 4;
+/// Room kept under the code for the caption
+const CAPTION_HEIGHT: i32 = 11;
+
+/// When the code on screen comes down, for [`qr_task`]
+static SHOWN_UNTIL: Signal<ThreadModeRawMutex, Instant> = Signal::new();
+
+/// The modules of an encoded QR code, one bit each and set for dark ones
+pub struct QrImage {
+    size: u8,
+    modules: [u8; (MAX_SIZE * MAX_SIZE + 7) / 8],
+}
+
+impl QrImage {
+    /// Encodes `text`, with more error correction when it is short enough. None if it does not
+    /// fit [`MAX_VERSION`]
+    pub fn encode(text: &str) -> Option<Self> {
+        let mut temp = [0u8; MAX_VERSION.buffer_len()];
+        let mut out = [0u8; MAX_VERSION.buffer_len()];
+        let code = QrCode::encode_text(
+            text,
+            &mut temp,
+            &mut out,
+            QrCodeEcc::Low,
+            Version::MIN,
+            MAX_VERSION,
+            None,
+            true,
+        )
+        .ok()?;
+        let size = code.size();
+        let mut image = QrImage {
+            size: size as u8,
+            modules: [0; (MAX_SIZE * MAX_SIZE + 7) / 8],
+        };
+        for y in 0..size {
+            for x in 0..size {
+                if code.get_module(x, y) {
+                    let index = (y * size + x) as usize;
+                    image.modules[index / 8] |= 1 << (index % 8);
+                }
+            }
+        }
+        Some(image)
+    }
+
+    fn is_dark(&self, x: i32, y: i32) -> bool {
+        let index = (y * self.size as i32 + x) as usize;
+        self.modules[index / 8] & 1 << (index % 8) != 0
+    }
+}
+
+/// Takes the code down at `until`, replacing the time for one already on screen
+pub fn show_until(until: Instant) {
+    SHOWN_UNTIL.signal(until);
+}
+
+/// The code as large as fits above `caption`. Scanners want dark modules on light, so the
+/// lighter of the text and background colors goes behind the code
+pub fn draw<D: Canvas>(
+    display: &mut D,
+    palette: &Palette<D::Color>,
+    image: &QrImage,
+    caption: &str,
+) {
+    let (light, dark) = match palette.text.is_lighter_than(palette.background) {
+        true => (palette.text, palette.background),
+        false => (palette.background, palette.text),
+    };
+    let size = display.bounding_box().size;
+    let (width, height) = (size.width as i32, size.height as i32);
+    let (quiet, scale) = fit(image.size as i32, width.min(height - CAPTION_HEIGHT));
+    let side = (image.size as i32 + 2 * quiet) * scale;
+    let corner = Point::new(
+        (width - side) / 2,
+        (height - CAPTION_HEIGHT - side).max(0) / 2,
+    );
+
+    let _ = Rectangle::new(corner, Size::new_equal(side as u32))
+        .into_styled(PrimitiveStyle::with_fill(light))
+        .draw(display);
+    let module = PrimitiveStyle::with_fill(dark);
+    for y in 0..image.size as i32 {
+        for x in 0..image.size as i32 {
+            if image.is_dark(x, y) {
+                let at = corner + Point::new(x + quiet, y + quiet) * scale;
+                let _ = Rectangle::new(at, Size::new_equal(scale as u32))
+                    .into_styled(module)
+                    .draw(display);
+            }
+        }
+    }
+    let _ = Text::with_baseline(
+        caption,
+        Point::new(width / 2, height),
+        palette.text(SMALL_FONT),
+        Baseline::Bottom,
+    )
+    .set_alignment(Alignment::Center)
+    .draw(display);
+}
+
+/// The quiet zone and pixels per module for a code `size` modules across in a square of `room`
+/// pixels. The quiet zone shrinks only when the code does not fit with the full one
+fn fit(size: i32, room: i32) -> (i32, i32) {
+    let quiet = (0..=QUIET_ZONE)
+        .rev()
+        .find(|quiet| size + 2 * quiet <= room)
+        .unwrap_or(0);
+    (quiet, (room / (size + 2 * quiet)).max(1))
+}
+
+/// Takes the code down once its time is up. A new code meanwhile starts the time over
+#[embassy_executor::task]
+pub async fn qr_task(ui: &'static UiMutex) {
+    loop {
+        let mut until = SHOWN_UNTIL.wait().await;
+        while let Either::Second(later) = select(Timer::at(until), SHOWN_UNTIL.wait()).await {
+            until = later;
+        }
+        ui.lock().await.end_qr_code();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn largest_code_fits_above_the_caption() {
+        let room = 64 - CAPTION_HEIGHT;
+        let (quiet, scale) = fit(MAX_SIZE as i32, room);
+        assert_eq!(quiet, QUIET_ZONE);
+        assert!((MAX_SIZE as i32 + 2 * quiet) * scale <= room);
+    }
+
+    #[test]
+    fn quiet_zone_shrinks_before_the_code_does() {
+        assert_eq!(fit(21, 200), (4, 6));
+        assert_eq!(fit(41, 45), (2, 1));
+        assert_eq!(fit(41, 40), (0, 1));
+    }
+}
@@ -0,0 +1,90 @@
+//! A WS2812 (NeoPixel) status LED, or a short strip of them, driven by a PIO state machine
+
+use crate::{led::triangle_wave, power};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use embassy_rp::{peripherals::PIO0, pio_programs::ws2812::PioWs2812};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use icd::{Rgb, RgbEffect};
+use smart_leds::RGB8;
+
+/// How many LEDs are chained on the data pin
+pub const RGB_LED_COUNT: usize = 1;
+/// WS2812s are blinding at full power, every channel is scaled down to this
+const MAX_BRIGHTNESS: u16 = 64;
+/// How long a pulse takes in the status effect while alerting
+const ALERT_PULSE_MS: u16 = 1000;
+
+const GREEN: Rgb = Rgb { r: 0, g: 255, b: 0 };
+const RED: Rgb = Rgb { r: 255, g: 0, b: 0 };
+
+pub type StatusLed = PioWs2812<'static, PIO0, 1, RGB_LED_COUNT>;
+
+/// The effect to switch to, set by the [`SetRgbLedEndpoint`](icd::SetRgbLedEndpoint) handler
+pub static EFFECT: Signal<ThreadModeRawMutex, RgbEffect> = Signal::new();
+/// Latest CPU usage from the host, used by [`RgbEffect::Status`]
+static CPU_USAGE: AtomicU8 = AtomicU8::new(0);
+/// Set while any metric is over its alert threshold
+static ALERTING: AtomicBool = AtomicBool::new(false);
+
+/// Updates what the status effect shows
+pub fn update_status(cpu_usage: u8, alerting: bool) {
+    CPU_USAGE.store(cpu_usage, Ordering::Relaxed);
+    ALERTING.store(alerting, Ordering::Relaxed);
+}
+
+/// Redraws the LEDs at 50Hz so pulses stay smooth. They are dark while USB is suspended
+#[embassy_executor::task]
+pub async fn rgb_task(mut led: StatusLed) {
+    let mut effect = RgbEffect::Status;
+    let mut ticker = Ticker::every(Duration::from_millis(20));
+    loop {
+        if let Some(new_effect) = EFFECT.try_take() {
+            effect = new_effect;
+        }
+        if power::is_suspended() {
+            led.write(&[RGB8::default(); RGB_LED_COUNT]).await;
+            Timer::after(power::SUSPENDED_POLL).await;
+            ticker.reset();
+            continue;
+        }
+        let color = color_for(&effect, Instant::now());
+        led.write(&[dim(color); RGB_LED_COUNT]).await;
+        ticker.next().await;
+    }
+}
+
+fn color_for(effect: &RgbEffect, now: Instant) -> Rgb {
+    match *effect {
+        RgbEffect::Status if ALERTING.load(Ordering::Relaxed) => pulse(RED, ALERT_PULSE_MS, now),
+        RgbEffect::Status => blend(GREEN, RED, CPU_USAGE.load(Ordering::Relaxed)),
+        RgbEffect::Solid(color) => color,
+        RgbEffect::Pulse { color, period_ms } => pulse(color, period_ms, now),
+        RgbEffect::Gradient { low, high, value } => blend(low, high, value),
+    }
+}
+
+/// Scales `color` by a triangle wave, fully off at the start of each period and full at the middle
+fn pulse(color: Rgb, period_ms: u16, now: Instant) -> Rgb {
+    blend(
+        Rgb { r: 0, g: 0, b: 0 },
+        color,
+        triangle_wave(period_ms, now),
+    )
+}
+
+/// Mixes `low` and `high`, `value` being the percentage of `high`
+fn blend(low: Rgb, high: Rgb, value: u8) -> Rgb {
+    let value = value.min(100) as u16;
+    let mix = |low: u8, high: u8| ((low as u16 * (100 - value) + high as u16 * value) / 100) as u8;
+    Rgb {
+        r: mix(low.r, high.r),
+        g: mix(low.g, high.g),
+        b: mix(low.b, high.b),
+    }
+}
+
+fn dim(color: Rgb) -> RGB8 {
+    let scale = |c: u8| (c as u16 * MAX_BRIGHTNESS / 255) as u8;
+    RGB8::new(scale(color.r), scale(color.g), scale(color.b))
+}
@@ -0,0 +1,79 @@
+//! Keeps the [`DeviceSettings`] in the last sector of flash so they survive a power cycle.
+//!
+//! The layout of the record, and how records of older firmware are read, is in
+//! [`icd::settings_record`]. A firmware update keeps the settings and name that way.
+
+use crate::logging;
+use core::cell::RefCell;
+use embassy_rp::flash::{Blocking, Error, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::{
+    blocking_mutex::{
+        self,
+        raw::{NoopRawMutex, ThreadModeRawMutex},
+    },
+    mutex::Mutex,
+};
+use icd::settings_record::{self, RECORD_SIZE, VERSION};
+use icd::DeviceSettings;
+
+/// Size of the flash, this has to match memory.x
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+/// The settings live in the last sector, which memory.x leaves out of the FLASH and DFU regions
+const SETTINGS_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+
+pub type AppFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+/// The flash is shared with the firmware updater, this is the mutex embassy-boot expects
+pub type SharedFlash = blocking_mutex::Mutex<NoopRawMutex, RefCell<AppFlash>>;
+pub type SettingsMutex = Mutex<ThreadModeRawMutex, SettingsStore>;
+
+pub struct SettingsStore {
+    flash: &'static SharedFlash,
+    current: DeviceSettings,
+}
+
+impl SettingsStore {
+    /// Reads the saved settings, falling back to the defaults if nothing valid has been saved yet
+    pub fn load(flash: &'static SharedFlash) -> Self {
+        let mut record = [0u8; RECORD_SIZE];
+        let read = flash.lock(|f| f.borrow_mut().blocking_read(SETTINGS_OFFSET, &mut record));
+        let current = match read {
+            Ok(()) => match settings_record::decode(&record) {
+                Some((version, settings)) => {
+                    if version != VERSION {
+                        logging::info!("Carried the settings over from version {}", version);
+                    }
+                    settings
+                }
+                None => {
+                    if !settings_record::is_erased(&record) {
+                        logging::warn!("Could not read the saved settings, using the defaults");
+                    }
+                    DeviceSettings::default()
+                }
+            },
+            Err(_) => DeviceSettings::default(),
+        };
+        SettingsStore { flash, current }
+    }
+
+    pub fn get(&self) -> &DeviceSettings {
+        &self.current
+    }
+
+    /// Writes the settings to flash. Nothing is written if they did not change
+    pub fn save(&mut self, settings: DeviceSettings) -> Result<(), Error> {
+        if settings == self.current {
+            return Ok(());
+        }
+
+        let record = settings_record::encode(&settings);
+        self.flash.lock(|f| {
+            let mut flash = f.borrow_mut();
+            flash.blocking_erase(SETTINGS_OFFSET, SETTINGS_OFFSET + ERASE_SIZE as u32)?;
+            flash.blocking_write(SETTINGS_OFFSET, &record)
+        })?;
+        self.current = settings;
+        Ok(())
+    }
+}
@@ -0,0 +1,456 @@
+//! Small color displays on SPI, in place of the SSD1306 when built with one of the `st7735`,
+//! `st7789` or `ssd1351` features.
+//!
+//! The pages keep their 128x64 layout. Panels with room for it show them at double size, and
+//! the layout is centered on the panel. Like [`crate::oled`] only the changed part of each row is
+//! sent, a whole frame is a lot more bytes here.
+
+use crate::display::changed_columns;
+use core::convert::Infallible;
+use core::ops::Range;
+use embassy_rp::gpio::Output;
+use embassy_rp::peripherals::SPI0;
+use embassy_rp::pwm::{self, Pwm};
+use embassy_rp::spi::{self, Spi};
+use embassy_time::{Instant, Timer};
+use embedded_graphics::{
+    pixelcolor::{IntoStorage, Rgb565},
+    prelude::*,
+};
+use icd::{Brightness, DeviceSettings, Rotation};
+use static_cell::ConstStaticCell;
+
+#[cfg(any(
+    all(feature = "st7735", feature = "st7789"),
+    all(feature = "st7735", feature = "ssd1351"),
+    all(feature = "st7789", feature = "ssd1351"),
+))]
+compile_error!("Only one of the st7735, st7789 and ssd1351 features can be enabled");
+
+/// Size of the layout the pages are drawn in
+const WIDTH: usize = 128;
+const HEIGHT: usize = 64;
+
+/// How a controller is set up and addressed
+struct Controller {
+    /// Panel size in landscape
+    width: usize,
+    height: usize,
+    /// Every pixel of the layout is drawn as a `scale` by `scale` square
+    scale: usize,
+    spi_hz: u32,
+    /// Commands with their arguments and how long to wait after each, sent after a reset
+    init: &'static [(u8, &'static [u8], u64)],
+    set_columns: u8,
+    set_rows: u8,
+    write_pixels: u8,
+    /// Column and row addresses are two bytes each on the DCS controllers, one on the SSD1351
+    wide_addresses: bool,
+    /// Takes the orientation, one of `normal` and `flipped`
+    orientation: u8,
+    normal: u8,
+    flipped: u8,
+    display_on: u8,
+    display_off: u8,
+    /// The SSD1351 is an OLED without a backlight, it dims with its master contrast instead
+    contrast: Option<u8>,
+}
+
+#[cfg(feature = "st7735")]
+const CONTROLLER: Controller = Controller {
+    width: 160,
+    height: 128,
+    scale: 1,
+    spi_hz: 16_000_000,
+    init: &[
+        (0x01, &[], 150),   //Software reset
+        (0x11, &[], 120),   //Out of sleep
+        (0x3A, &[0x05], 0), //16 bits per pixel
+        (0x13, &[], 0),     //Normal display mode
+    ],
+    set_columns: 0x2A,
+    set_rows: 0x2B,
+    write_pixels: 0x2C,
+    wide_addresses: true,
+    orientation: 0x36,
+    //Landscape, with the blue and red channels swapped like most ST7735 modules
+    normal: 0x68,
+    flipped: 0xA8,
+    display_on: 0x29,
+    display_off: 0x28,
+    contrast: None,
+};
+
+#[cfg(feature = "st7789")]
+const CONTROLLER: Controller = Controller {
+    width: 320,
+    height: 240,
+    scale: 2,
+    spi_hz: 62_500_000,
+    init: &[
+        (0x01, &[], 150),   //Software reset
+        (0x11, &[], 120),   //Out of sleep
+        (0x3A, &[0x55], 0), //16 bits per pixel
+        (0x21, &[], 0),     //Inverted, ST7789 modules are wired for it
+        (0x13, &[], 0),     //Normal display mode
+    ],
+    set_columns: 0x2A,
+    set_rows: 0x2B,
+    write_pixels: 0x2C,
+    wide_addresses: true,
+    orientation: 0x36,
+    //Landscape
+    normal: 0x60,
+    flipped: 0xA0,
+    display_on: 0x29,
+    display_off: 0x28,
+    contrast: None,
+};
+
+#[cfg(feature = "ssd1351")]
+const CONTROLLER: Controller = Controller {
+    width: 128,
+    height: 128,
+    scale: 1,
+    spi_hz: 20_000_000,
+    init: &[
+        (0xFD, &[0x12], 0),             //Unlock the driver
+        (0xFD, &[0xB1], 0),             //Unlock the commands below
+        (0xAE, &[], 0),                 //Display off while setting up
+        (0xB3, &[0xF1], 0),             //Clock divider and oscillator
+        (0xCA, &[0x7F], 0),             //All 128 rows
+        (0xA1, &[0x00], 0),             //Start line
+        (0xA2, &[0x00], 0),             //Display offset
+        (0xB5, &[0x00], 0),             //GPIO off
+        (0xAB, &[0x01], 0),             //Internal regulator
+        (0xB1, &[0x32], 0),             //Precharge
+        (0xBE, &[0x05], 0),             //VCOMH
+        (0xA6, &[], 0),                 //Normal display mode
+        (0xC1, &[0xC8, 0x80, 0xC8], 0), //Contrast per color
+        (0xB4, &[0xA0, 0xB5, 0x55], 0), //Segment low voltage
+        (0xB6, &[0x01], 0),             //Second precharge
+    ],
+    set_columns: 0x15,
+    set_rows: 0x75,
+    write_pixels: 0x5C,
+    wide_addresses: false,
+    orientation: 0xA0,
+    //16 bits per pixel with the COM lines split, the flipped one mirrors columns and rows
+    normal: 0x74,
+    flipped: 0x66,
+    display_on: 0xAF,
+    display_off: 0xAE,
+    contrast: Some(0xC7),
+};
+
+/// Where the layout starts on the panel, centered
+const LEFT: usize = (CONTROLLER.width - WIDTH * CONTROLLER.scale) / 2;
+const TOP: usize = (CONTROLLER.height - HEIGHT * CONTROLLER.scale) / 2;
+
+/// Backlight PWM counts to this
+const BACKLIGHT_TOP: u16 = 0x8000;
+
+pub fn spi_config() -> spi::Config {
+    let mut config = spi::Config::default();
+    config.frequency = CONTROLLER.spi_hz;
+    config
+}
+
+/// The 128x64 layout in color, a pixel at a time
+#[derive(Clone)]
+pub struct Frame {
+    pixels: [Rgb565; WIDTH * HEIGHT],
+}
+
+impl Frame {
+    const fn new() -> Self {
+        Frame {
+            pixels: [Rgb565::BLACK; WIDTH * HEIGHT],
+        }
+    }
+}
+
+impl OriginDimensions for Frame {
+    fn size(&self) -> Size {
+        Size::new(WIDTH as u32, HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for Frame {
+    type Color = Rgb565;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Rgb565>>,
+    {
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (usize::try_from(point.x), usize::try_from(point.y)) else {
+                continue;
+            };
+            if x < WIDTH && y < HEIGHT {
+                self.pixels[y * WIDTH + x] = color;
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Rgb565) -> Result<(), Self::Error> {
+        self.pixels.fill(color);
+        Ok(())
+    }
+}
+
+/// Owns the SPI panel. Only the render task uses it
+pub struct Panel {
+    spi: Spi<'static, SPI0, spi::Async>,
+    cs: Output<'static>,
+    /// Low while sending a command, high for its arguments and pixels
+    dc: Output<'static>,
+    reset: Output<'static>,
+    backlight: Pwm<'static>,
+    /// Drawn into by the ui. Both frames are 16 KiB, too big to move around with the render task
+    frame: &'static mut Frame,
+    /// What the panel is showing, if `in_sync`
+    shown: &'static mut Frame,
+    /// False until the first full frame after an init
+    in_sync: bool,
+    /// The panel is switched off while USB is suspended
+    on: bool,
+    /// What was last sent to the panel, `None` after an init
+    configured: Option<(Brightness, Rotation)>,
+}
+
+impl Panel {
+    /// Can only be called once, the frames are statics
+    pub fn new(
+        spi: Spi<'static, SPI0, spi::Async>,
+        cs: Output<'static>,
+        dc: Output<'static>,
+        reset: Output<'static>,
+        backlight: Pwm<'static>,
+    ) -> Self {
+        static FRAME: ConstStaticCell<Frame> = ConstStaticCell::new(Frame::new());
+        static SHOWN: ConstStaticCell<Frame> = ConstStaticCell::new(Frame::new());
+        Panel {
+            spi,
+            cs,
+            dc,
+            reset,
+            backlight,
+            frame: FRAME.take(),
+            shown: SHOWN.take(),
+            in_sync: false,
+            on: true,
+            configured: None,
+        }
+    }
+
+    /// Resets and sets up the panel. Nothing answers on SPI, so this only fails if the transfer
+    /// itself does
+    pub async fn init(&mut self) -> bool {
+        self.reset.set_low();
+        Timer::after_millis(10).await;
+        self.reset.set_high();
+        Timer::after_millis(120).await;
+
+        for (command, args, wait_ms) in CONTROLLER.init {
+            if self.command(*command, args).await.is_err() {
+                return false;
+            }
+            Timer::after_millis(*wait_ms).await;
+        }
+        //The panel's memory is random after a reset, so the area around the layout is cleared
+        //once here instead of on every frame
+        if self.clear_panel().await.is_err() {
+            return false;
+        }
+        if self.command(CONTROLLER.display_on, &[]).await.is_err() {
+            return false;
+        }
+        self.in_sync = false;
+        self.on = true;
+        self.configured = None;
+        true
+    }
+
+    pub fn frame(&mut self) -> &mut Frame {
+        self.frame
+    }
+
+    /// Frames go out as fast as they are drawn
+    pub fn next_refresh(&self) -> Option<Instant> {
+        None
+    }
+
+    pub async fn set_on(&mut self, on: bool) -> Result<(), ()> {
+        if self.on != on {
+            let command = match on {
+                true => CONTROLLER.display_on,
+                false => CONTROLLER.display_off,
+            };
+            self.command(command, &[]).await?;
+            //The backlight follows on the next configure
+            self.configured = None;
+            if !on {
+                self.set_backlight(0);
+            }
+            self.on = on;
+        }
+        Ok(())
+    }
+
+    /// Sends brightness and rotation if they changed
+    pub async fn configure(&mut self, settings: &DeviceSettings) -> Result<(), ()> {
+        let wanted = (settings.brightness, settings.rotation);
+        if self.configured == Some(wanted) {
+            return Ok(());
+        }
+
+        let percent = match settings.brightness {
+            Brightness::Dimmest => 10,
+            Brightness::Dim => 25,
+            Brightness::Normal => 50,
+            Brightness::Brighter => 75,
+            Brightness::Brightest => 100,
+        };
+        self.set_backlight(percent);
+        if let Some(contrast) = CONTROLLER.contrast {
+            //Master contrast goes from 0 to 15
+            self.command(contrast, &[(percent * 15 / 100) as u8])
+                .await?;
+        }
+
+        let orientation = match settings.rotation {
+            Rotation::Normal => CONTROLLER.normal,
+            Rotation::Flipped => CONTROLLER.flipped,
+        };
+        self.command(CONTROLLER.orientation, &[orientation]).await?;
+        self.configured = Some(wanted);
+        Ok(())
+    }
+
+    /// Sends the changed part of each row, or everything if what the panel shows is not known.
+    /// Returns how many bytes of pixels went out
+    pub async fn flush(&mut self) -> Result<usize, ()> {
+        let mut sent = 0;
+        for y in 0..HEIGHT {
+            let row = y * WIDTH..(y + 1) * WIDTH;
+            let (new, old) = (&self.frame.pixels[row.clone()], &self.shown.pixels[row]);
+            let columns = match self.in_sync {
+                true => match changed_columns(new, old) {
+                    Some(columns) => columns,
+                    None => continue,
+                },
+                false => 0..WIDTH,
+            };
+            sent += self.send_row(y, columns).await?;
+        }
+        self.shown.clone_from(self.frame);
+        self.in_sync = true;
+        Ok(sent)
+    }
+
+    /// Sends `columns` of layout row `y`, every pixel as a `scale` by `scale` square.
+    /// Returns how many bytes that took
+    async fn send_row(&mut self, y: usize, columns: Range<usize>) -> Result<usize, ()> {
+        let scale = CONTROLLER.scale;
+        let left = LEFT + columns.start * scale;
+        let top = TOP + y * scale;
+        self.set_window(left, top, columns.len() * scale, scale)
+            .await?;
+
+        let mut line = [0u8; WIDTH * 2 * CONTROLLER.scale];
+        let mut length = 0;
+        for color in &self.frame.pixels[y * WIDTH..][columns] {
+            let bytes = color.into_storage().to_be_bytes();
+            for _ in 0..scale {
+                line[length..length + 2].copy_from_slice(&bytes);
+                length += 2;
+            }
+        }
+        self.start(CONTROLLER.write_pixels).await?;
+        for _ in 0..scale {
+            self.spi.write(&line[..length]).await.map_err(drop)?;
+        }
+        self.cs.set_high();
+        Ok(length * scale)
+    }
+
+    /// Fills the whole panel with black
+    async fn clear_panel(&mut self) -> Result<(), ()> {
+        self.set_window(0, 0, CONTROLLER.width, CONTROLLER.height)
+            .await?;
+        let line = [0u8; 2 * 320];
+        self.start(CONTROLLER.write_pixels).await?;
+        for _ in 0..CONTROLLER.height {
+            self.spi
+                .write(&line[..CONTROLLER.width * 2])
+                .await
+                .map_err(drop)?;
+        }
+        self.cs.set_high();
+        Ok(())
+    }
+
+    /// Makes the next pixels written fill this rectangle, row by row
+    async fn set_window(
+        &mut self,
+        left: usize,
+        top: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<(), ()> {
+        let (right, bottom) = (left + width - 1, top + height - 1);
+        if CONTROLLER.wide_addresses {
+            let columns = [
+                (left >> 8) as u8,
+                left as u8,
+                (right >> 8) as u8,
+                right as u8,
+            ];
+            let rows = [
+                (top >> 8) as u8,
+                top as u8,
+                (bottom >> 8) as u8,
+                bottom as u8,
+            ];
+            self.command(CONTROLLER.set_columns, &columns).await?;
+            self.command(CONTROLLER.set_rows, &rows).await
+        } else {
+            self.command(CONTROLLER.set_columns, &[left as u8, right as u8])
+                .await?;
+            self.command(CONTROLLER.set_rows, &[top as u8, bottom as u8])
+                .await
+        }
+    }
+
+    async fn command(&mut self, command: u8, args: &[u8]) -> Result<(), ()> {
+        self.start(command).await?;
+        let sent = match args.is_empty() {
+            true => Ok(()),
+            false => self.spi.write(args).await.map_err(drop),
+        };
+        self.cs.set_high();
+        sent
+    }
+
+    /// Sends `command` and leaves the panel selected for its arguments
+    async fn start(&mut self, command: u8) -> Result<(), ()> {
+        self.cs.set_low();
+        self.dc.set_low();
+        let sent = self.spi.write(&[command]).await.map_err(drop);
+        self.dc.set_high();
+        if sent.is_err() {
+            self.cs.set_high();
+        }
+        sent
+    }
+
+    fn set_backlight(&mut self, percent: u16) {
+        let mut config = pwm::Config::default();
+        config.top = BACKLIGHT_TOP;
+        config.compare_a = (BACKLIGHT_TOP as u32 * percent as u32 / 100) as u16;
+        self.backlight.set_config(&config);
+    }
+}
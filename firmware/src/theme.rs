@@ -0,0 +1,106 @@
+//! Colors the pages are drawn in, so the same drawing code works on the SSD1306 and color displays.
+//!
+//! Drawing code asks a [`Palette`] for what a color is for (text, a busy bar, ...). On the SSD1306
+//! everything but the background is simply on, color displays take the [`Theme`] set by the host.
+
+use embedded_graphics::{
+    mono_font::{MonoFont, MonoTextStyle, MonoTextStyleBuilder},
+    pixelcolor::{BinaryColor, Rgb565, Rgb888},
+    prelude::*,
+};
+use icd::{Rgb, Theme};
+
+/// Bars at or over this percentage are drawn in the busy color
+const BUSY_FROM: u8 = 60;
+/// Bars at or over this percentage are drawn in the critical color
+const CRITICAL_FROM: u8 = 85;
+
+/// A pixel color the pages can be drawn in
+pub trait UiColor: PixelColor {
+    fn palette(theme: &Theme) -> Palette<Self>;
+
+    /// Whether this looks lighter than `other` on the panel
+    fn is_lighter_than(self, other: Self) -> bool;
+}
+
+/// Anything the pages can be drawn on
+pub trait Canvas: DrawTarget<Color: UiColor> {}
+
+impl<D: DrawTarget<Color: UiColor>> Canvas for D {}
+
+#[derive(Clone, Copy)]
+pub struct Palette<C> {
+    pub background: C,
+    pub text: C,
+    /// Behind banners, the alert marker and other inverted text
+    pub highlight: C,
+    pub good: C,
+    pub busy: C,
+    pub critical: C,
+}
+
+impl<C: UiColor> Palette<C> {
+    pub fn text(&self, font: &'static MonoFont<'static>) -> MonoTextStyle<'static, C> {
+        MonoTextStyle::new(font, self.text)
+    }
+
+    /// Background colored text on the highlight color
+    pub fn inverted(&self, font: &'static MonoFont<'static>) -> MonoTextStyle<'static, C> {
+        MonoTextStyleBuilder::new()
+            .font(font)
+            .text_color(self.background)
+            .background_color(self.highlight)
+            .build()
+    }
+
+    /// Green, yellow or red for a bar at `percent`, or whatever the theme has instead
+    pub fn load(&self, percent: u8) -> C {
+        match percent {
+            p if p >= CRITICAL_FROM => self.critical,
+            p if p >= BUSY_FROM => self.busy,
+            _ => self.good,
+        }
+    }
+}
+
+impl UiColor for BinaryColor {
+    fn palette(_theme: &Theme) -> Palette<Self> {
+        Palette {
+            background: BinaryColor::Off,
+            text: BinaryColor::On,
+            highlight: BinaryColor::On,
+            good: BinaryColor::On,
+            busy: BinaryColor::On,
+            critical: BinaryColor::On,
+        }
+    }
+
+    fn is_lighter_than(self, other: Self) -> bool {
+        //On is a lit pixel on the SSD1306 but black ink on e-paper
+        let light = match cfg!(feature = "epaper") {
+            true => BinaryColor::Off,
+            false => BinaryColor::On,
+        };
+        self == light && other != light
+    }
+}
+
+impl UiColor for Rgb565 {
+    fn palette(theme: &Theme) -> Palette<Self> {
+        let color = |rgb: Rgb| Rgb565::from(Rgb888::new(rgb.r, rgb.g, rgb.b));
+        Palette {
+            background: color(theme.background),
+            text: color(theme.text),
+            highlight: color(theme.highlight),
+            good: color(theme.good),
+            busy: color(theme.busy),
+            critical: color(theme.critical),
+        }
+    }
+
+    fn is_lighter_than(self, other: Self) -> bool {
+        //Rough luma, red and blue count double to make up for green's extra bit
+        let luma = |c: Rgb565| 6 * c.r() as u32 + 6 * c.g() as u32 + 2 * c.b() as u32;
+        luma(self) > luma(other)
+    }
+}
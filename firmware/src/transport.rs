@@ -0,0 +1,617 @@
+//! The USB side of postcard-rpc, as a composite device with two ways in:
+//!
+//! - The vendor interface poststation looks for, with one postcard-rpc frame per USB transfer.
+//!   This is the same as postcard-rpc's own `init_poststation`.
+//! - A CDC-ACM serial port for hosts without poststation. Every frame is COBS encoded and
+//!   ended with a 0 byte, the contents are the same header and postcard body.
+//!
+//! A reply goes back on the interface its request came in on. Topics and logs go out on every
+//! interface a request has come in on, so poststation and a serial host can both be attached.
+
+use crate::app::AppDriver;
+use crate::io::TextWriter;
+use crate::power;
+use core::cell::RefCell;
+use core::fmt::Arguments;
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{self, raw::ThreadModeRawMutex},
+    mutex::Mutex,
+};
+use embassy_time::Timer;
+use embassy_usb::{
+    class::cdc_acm::{CdcAcmClass, Receiver, Sender, State},
+    descriptor::lang_id,
+    driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut},
+    msos::{self, windows_version},
+    types::StringIndex,
+    Builder, Config, Handler, UsbDevice,
+};
+use heapless::Vec;
+use icd::{ENDPOINT_LIST, TOPICS_OUT_LIST};
+use postcard_rpc::{
+    header::{VarHeader, VarKey, VarKeyKind, VarSeq},
+    server::{
+        impls::embassy_usb_v0_4::dispatch_impl::DEVICE_INTERFACE_GUIDS, WireRx, WireRxErrorKind,
+        WireTx, WireTxErrorKind,
+    },
+    standard_icd::{LoggingTopic, ERROR_KEY},
+    Topic,
+};
+use serde::Serialize;
+use static_cell::{ConstStaticCell, StaticCell};
+
+/// Both interfaces use full speed bulk endpoints
+const PACKET_SIZE: usize = 64;
+/// Largest frame either way, the same as the postcard-rpc packet buffers
+const FRAME_SIZE: usize = 1024;
+/// A COBS encoded frame and its terminating 0
+const COBS_FRAME_SIZE: usize = FRAME_SIZE + FRAME_SIZE / 254 + 2;
+/// Formatted log messages longer than this are cut off
+const LOG_MESSAGE_SIZE: usize = 256;
+
+/// Requests that can wait for their reply at once, postcard-rpc spawns up to a few handlers
+const MAX_PENDING: usize = 8;
+
+/// Where replies and topics go, filled in as requests come in
+static ROUTES: blocking_mutex::Mutex<ThreadModeRawMutex, RefCell<Routes>> =
+    blocking_mutex::Mutex::new(RefCell::new(Routes::new()));
+
+struct UsbBuffers {
+    config_descriptor: [u8; 256],
+    bos_descriptor: [u8; 256],
+    msos_descriptor: [u8; 256],
+    control_buf: [u8; 64],
+}
+
+static USB_BUFFERS: ConstStaticCell<UsbBuffers> = ConstStaticCell::new(UsbBuffers {
+    config_descriptor: [0; 256],
+    bos_descriptor: [0; 256],
+    msos_descriptor: [0; 256],
+    control_buf: [0; 64],
+});
+static COBS_BUF: ConstStaticCell<[u8; COBS_FRAME_SIZE]> =
+    ConstStaticCell::new([0; COBS_FRAME_SIZE]);
+static RX_STATE: ConstStaticCell<RxState> = ConstStaticCell::new(RxState::new());
+static CDC_STATE: StaticCell<State<'static>> = StaticCell::new();
+static HANDLER: StaticCell<PoststationHandler> = StaticCell::new();
+static TX_INNER: StaticCell<Mutex<ThreadModeRawMutex, TxInner>> = StaticCell::new();
+
+/// Names the vendor interface "Poststation", which is how poststation tells it is one of its own
+struct PoststationHandler {
+    index: StringIndex,
+}
+
+impl Handler for PoststationHandler {
+    fn get_string(&mut self, index: StringIndex, lang_id: u16) -> Option<&str> {
+        (lang_id == lang_id::ENGLISH_US && index.0 == self.index.0).then_some("Poststation")
+    }
+}
+
+/// Builds the USB device with both interfaces. This must only be called once
+pub fn init(
+    driver: AppDriver,
+    config: Config<'static>,
+    tx_buf: &'static mut [u8],
+) -> (UsbDevice<'static, AppDriver>, AppTx, AppRx) {
+    let bufs = USB_BUFFERS.take();
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut bufs.config_descriptor,
+        &mut bufs.bos_descriptor,
+        &mut bufs.msos_descriptor,
+        &mut bufs.control_buf,
+    );
+
+    // Only the vendor function gets the WinUSB driver on Windows, the serial port keeps the
+    // standard CDC-ACM one
+    builder.msos_descriptor(windows_version::WIN8_1, 0);
+    let mut function = builder.function(0xFF, 0, 0);
+    function.msos_feature(msos::CompatibleIdFeatureDescriptor::new("WINUSB", ""));
+    function.msos_feature(msos::RegistryPropertyFeatureDescriptor::new(
+        "DeviceInterfaceGUIDs",
+        msos::PropertyData::RegMultiSz(DEVICE_INTERFACE_GUIDS),
+    ));
+    let mut interface = function.interface();
+    let index = interface.string();
+    let mut alt = interface.alt_setting(0xFF, 0xCA, 0x7D, Some(index));
+    let ep_out = alt.endpoint_bulk_out(PACKET_SIZE as u16);
+    let ep_in = alt.endpoint_bulk_in(PACKET_SIZE as u16);
+    drop(function);
+    builder.handler(HANDLER.init(PoststationHandler { index }));
+    builder.handler(power::HANDLER.take());
+
+    let serial = CdcAcmClass::new(
+        &mut builder,
+        CDC_STATE.init(State::new()),
+        PACKET_SIZE as u16,
+    );
+    let (serial_tx, serial_rx) = serial.split();
+
+    let inner = TX_INNER.init(Mutex::new(TxInner {
+        ep_in,
+        serial: serial_tx,
+        tx_buf,
+        cobs_buf: COBS_BUF.take(),
+        log_seq: 0,
+        pending_frame: false,
+    }));
+
+    let usb = builder.build();
+    (
+        usb,
+        AppTx { inner },
+        AppRx {
+            ep_out,
+            serial: serial_rx,
+            state: RX_STATE.take(),
+        },
+    )
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// Routing
+//////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, PartialEq)]
+enum Interface {
+    Usb,
+    Serial,
+}
+
+/// A request still waiting for its reply
+struct PendingReply {
+    seq: VarSeq,
+    /// The reply key of the endpoint, None for endpoints missing from the ICD
+    key: Option<VarKey>,
+    from: Interface,
+}
+
+/// Which interfaces a frame goes out on
+#[derive(Clone, Copy)]
+struct Destinations {
+    usb: bool,
+    serial: bool,
+}
+
+struct Routes {
+    /// Oldest first, the oldest is dropped when a new request does not fit
+    pending: Vec<PendingReply, MAX_PENDING>,
+    /// Interfaces a request came in on since the last failed send on them
+    attached: Destinations,
+}
+
+impl Routes {
+    const fn new() -> Self {
+        Routes {
+            pending: Vec::new(),
+            attached: Destinations {
+                usb: false,
+                serial: false,
+            },
+        }
+    }
+
+    /// Remembers where a request came in so its reply goes back there
+    fn request(&mut self, frame: &[u8], from: Interface) {
+        match from {
+            Interface::Usb => self.attached.usb = true,
+            Interface::Serial => self.attached.serial = true,
+        }
+        let Some((hdr, _)) = VarHeader::take_from_slice(frame) else {
+            return;
+        };
+        let key = ENDPOINT_LIST
+            .endpoints
+            .iter()
+            .find(|(_, request, _)| VarKey::Key8(*request) == hdr.key)
+            .map(|(_, _, reply)| VarKey::Key8(*reply));
+        if self.pending.is_full() {
+            self.pending.remove(0);
+        }
+        let _ = self.pending.push(PendingReply {
+            seq: hdr.seq_no,
+            key,
+            from,
+        });
+    }
+
+    /// A reply goes where its request came from, anything else to every attached interface.
+    /// Before any request it is the vendor interface, like plain postcard-rpc
+    fn destinations(&mut self, hdr: &VarHeader) -> Destinations {
+        let everywhere = match self.attached {
+            Destinations {
+                usb: false,
+                serial: false,
+            } => Destinations {
+                usb: true,
+                serial: false,
+            },
+            attached => attached,
+        };
+        if is_topic(&hdr.key) {
+            return everywhere;
+        }
+        let reply = self.pending.iter().position(|pending| {
+            pending.seq == hdr.seq_no
+                && (pending.key.is_none_or(|key| key == hdr.key)
+                    || hdr.key == VarKey::Key8(ERROR_KEY))
+        });
+        match reply.map(|index| self.pending.remove(index).from) {
+            Some(Interface::Usb) => Destinations {
+                usb: true,
+                serial: false,
+            },
+            Some(Interface::Serial) => Destinations {
+                usb: false,
+                serial: true,
+            },
+            None => everywhere,
+        }
+    }
+
+    /// Stops sending topics to an interface nobody reads, until the next request on it
+    fn detach(&mut self, interface: Interface) {
+        match interface {
+            Interface::Usb => self.attached.usb = false,
+            Interface::Serial => self.attached.serial = false,
+        }
+    }
+}
+
+fn is_topic(key: &VarKey) -> bool {
+    *key == VarKey::Key8(LoggingTopic::TOPIC_KEY)
+        || TOPICS_OUT_LIST
+            .topics
+            .iter()
+            .any(|(_, topic)| VarKey::Key8(*topic) == *key)
+}
+
+fn with_routes<R>(f: impl FnOnce(&mut Routes) -> R) -> R {
+    ROUTES.lock(|routes| f(&mut routes.borrow_mut()))
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// TX
+//////////////////////////////////////////////////////////////////////////////
+
+struct TxInner {
+    ep_in: <AppDriver as Driver<'static>>::EndpointIn,
+    serial: Sender<'static, AppDriver>,
+    tx_buf: &'static mut [u8],
+    cobs_buf: &'static mut [u8; COBS_FRAME_SIZE],
+    log_seq: u16,
+    /// A USB frame was cut off by a timeout and still needs its end
+    pending_frame: bool,
+}
+
+impl TxInner {
+    /// Writes the header and body to the tx buffer and sends them as one frame
+    async fn send_message<T: Serialize + ?Sized>(
+        &mut self,
+        hdr: VarHeader,
+        msg: &T,
+    ) -> Result<(), WireTxErrorKind> {
+        let (hdr_used, remain) = hdr
+            .write_to_slice(self.tx_buf)
+            .ok_or(WireTxErrorKind::Other)?;
+        let hdr_len = hdr_used.len();
+        let body_len = postcard::to_slice(msg, remain)
+            .map_err(|_| WireTxErrorKind::Other)?
+            .len();
+        self.send_frame(hdr_len + body_len).await
+    }
+
+    /// Sends the first `len` bytes of the tx buffer on the interfaces it is meant for. Fails only
+    /// if it went out on none of them
+    async fn send_frame(&mut self, len: usize) -> Result<(), WireTxErrorKind> {
+        let TxInner {
+            ep_in,
+            serial,
+            tx_buf,
+            cobs_buf,
+            pending_frame,
+            ..
+        } = self;
+        let frame = tx_buf.get(..len).ok_or(WireTxErrorKind::Other)?;
+        let (hdr, _) = VarHeader::take_from_slice(frame).ok_or(WireTxErrorKind::Other)?;
+        let topic = is_topic(&hdr.key);
+        let to = with_routes(|routes| routes.destinations(&hdr));
+
+        let mut result = Err(WireTxErrorKind::Other);
+        if to.usb {
+            let sent = send_usb(ep_in, frame, pending_frame).await;
+            if sent.is_err() && topic {
+                with_routes(|routes| routes.detach(Interface::Usb));
+            }
+            result = sent;
+        }
+        if to.serial {
+            //A frame too big to encode only fails on serial, the USB result above still counts
+            //and the link itself is fine, so it stays attached
+            match cobs::try_encode(frame, &mut cobs_buf[..COBS_FRAME_SIZE - 1]) {
+                Ok(encoded) => {
+                    cobs_buf[encoded] = 0;
+                    let sent = send_serial(serial, &cobs_buf[..=encoded]).await;
+                    if sent.is_err() && topic {
+                        with_routes(|routes| routes.detach(Interface::Serial));
+                    }
+                    result = result.or(sent);
+                }
+                Err(_) => result = result.or(Err(WireTxErrorKind::Other)),
+            }
+        }
+        result
+    }
+}
+
+/// Sends `out` as one transfer, ended by a short packet
+async fn send_usb(
+    ep_in: &mut <AppDriver as Driver<'static>>::EndpointIn,
+    out: &[u8],
+    pending_frame: &mut bool,
+) -> Result<(), WireTxErrorKind> {
+    if out.is_empty() {
+        return Ok(());
+    }
+
+    let send = async {
+        //Ends the frame a timeout left open so the host does not glue the two together
+        if *pending_frame && ep_in.write(&[]).await.is_err() {
+            return Err(WireTxErrorKind::ConnectionClosed);
+        }
+        *pending_frame = true;
+        for packet in out.chunks(PACKET_SIZE) {
+            ep_in
+                .write(packet)
+                .await
+                .map_err(|_| WireTxErrorKind::ConnectionClosed)?;
+        }
+        if out.len() % PACKET_SIZE == 0 && ep_in.write(&[]).await.is_err() {
+            return Err(WireTxErrorKind::ConnectionClosed);
+        }
+        *pending_frame = false;
+        Ok(())
+    };
+    with_timeout(out.len(), send).await
+}
+
+/// Sends an encoded frame over the serial port. The 0 at its end marks where it stops, so
+/// there is nothing to finish if it gets cut off
+async fn send_serial(
+    serial: &mut Sender<'static, AppDriver>,
+    out: &[u8],
+) -> Result<(), WireTxErrorKind> {
+    let send = async {
+        for packet in out.chunks(PACKET_SIZE) {
+            serial
+                .write_packet(packet)
+                .await
+                .map_err(|_| WireTxErrorKind::ConnectionClosed)?;
+        }
+        Ok(())
+    };
+    with_timeout(out.len(), send).await
+}
+
+/// Gives up on a send if the host is not reading, 2ms for every packet like postcard-rpc does
+async fn with_timeout(
+    len: usize,
+    send: impl core::future::Future<Output = Result<(), WireTxErrorKind>>,
+) -> Result<(), WireTxErrorKind> {
+    let timeout_ms = len.div_ceil(PACKET_SIZE) as u64 * 2;
+    match select(send, Timer::after_millis(timeout_ms)).await {
+        Either::First(result) => result,
+        Either::Second(()) => Err(WireTxErrorKind::Timeout),
+    }
+}
+
+/// Sends postcard-rpc frames to the hosts, see [`Routes`] for which interface gets what
+#[derive(Clone, Copy)]
+pub struct AppTx {
+    inner: &'static Mutex<ThreadModeRawMutex, TxInner>,
+}
+
+impl WireTx for AppTx {
+    type Error = WireTxErrorKind;
+
+    async fn send<T: Serialize + ?Sized>(
+        &self,
+        hdr: VarHeader,
+        msg: &T,
+    ) -> Result<(), Self::Error> {
+        self.inner.lock().await.send_message(hdr, msg).await
+    }
+
+    async fn send_raw(&self, buf: &[u8]) -> Result<(), Self::Error> {
+        let mut inner = self.inner.lock().await;
+        let len = buf.len();
+        inner
+            .tx_buf
+            .get_mut(..len)
+            .ok_or(WireTxErrorKind::Other)?
+            .copy_from_slice(buf);
+        inner.send_frame(len).await
+    }
+
+    async fn send_log_str(&self, kkind: VarKeyKind, s: &str) -> Result<(), Self::Error> {
+        let mut inner = self.inner.lock().await;
+        let key = match kkind {
+            VarKeyKind::Key1 => VarKey::Key1(LoggingTopic::TOPIC_KEY1),
+            VarKeyKind::Key2 => VarKey::Key2(LoggingTopic::TOPIC_KEY2),
+            VarKeyKind::Key4 => VarKey::Key4(LoggingTopic::TOPIC_KEY4),
+            VarKeyKind::Key8 => VarKey::Key8(LoggingTopic::TOPIC_KEY),
+        };
+        let seq = inner.log_seq;
+        inner.log_seq = seq.wrapping_add(1);
+        let hdr = VarHeader {
+            key,
+            seq_no: VarSeq::Seq2(seq),
+        };
+        inner.send_message(hdr, s).await
+    }
+
+    async fn send_log_fmt<'a>(
+        &self,
+        kkind: VarKeyKind,
+        a: Arguments<'a>,
+    ) -> Result<(), Self::Error> {
+        let mut buf = [0u8; LOG_MESSAGE_SIZE];
+        let mut writer = TextWriter::new(&mut buf);
+        let _ = core::fmt::write(&mut writer, a);
+        self.send_log_str(kkind, writer.as_str()).await
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// RX
+//////////////////////////////////////////////////////////////////////////////
+
+/// Frames put together so far on each interface. Kept between calls to `receive` so a frame
+/// coming in on one interface does not lose the half of another
+struct RxState {
+    usb: [u8; FRAME_SIZE],
+    usb_len: usize,
+    /// Set once a USB frame no longer fits, the rest of it is thrown away
+    usb_overflow: bool,
+    serial: [u8; FRAME_SIZE],
+    serial_len: usize,
+    serial_overflow: bool,
+    /// The last serial packet, which can hold the end of one frame and the start of the next
+    packet: [u8; PACKET_SIZE],
+    packet_pos: usize,
+    packet_len: usize,
+}
+
+impl RxState {
+    const fn new() -> Self {
+        RxState {
+            usb: [0; FRAME_SIZE],
+            usb_len: 0,
+            usb_overflow: false,
+            serial: [0; FRAME_SIZE],
+            serial_len: 0,
+            serial_overflow: false,
+            packet: [0; PACKET_SIZE],
+            packet_pos: 0,
+            packet_len: 0,
+        }
+    }
+
+    /// Adds a packet from the vendor interface, returns the frame length once a short packet
+    /// ends it
+    fn push_usb(&mut self, packet: &[u8]) -> Option<Result<usize, WireRxErrorKind>> {
+        let end = self.usb_len + packet.len();
+        if self.usb_overflow || end > FRAME_SIZE {
+            self.usb_overflow = true;
+        } else {
+            self.usb[self.usb_len..end].copy_from_slice(packet);
+            self.usb_len = end;
+        }
+        if packet.len() == PACKET_SIZE {
+            return None;
+        }
+
+        let result = if self.usb_overflow {
+            Err(WireRxErrorKind::ReceivedMessageTooLarge)
+        } else {
+            Ok(self.usb_len)
+        };
+        self.usb_len = 0;
+        self.usb_overflow = false;
+        Some(result)
+    }
+
+    /// Works through the last serial packet, returns the decoded length at the first 0
+    fn next_serial(&mut self) -> Option<Result<usize, WireRxErrorKind>> {
+        while self.packet_pos < self.packet_len {
+            let byte = self.packet[self.packet_pos];
+            self.packet_pos += 1;
+            if byte != 0 {
+                if self.serial_len < FRAME_SIZE {
+                    self.serial[self.serial_len] = byte;
+                    self.serial_len += 1;
+                } else {
+                    self.serial_overflow = true;
+                }
+                continue;
+            }
+
+            let result = if self.serial_overflow {
+                Err(WireRxErrorKind::ReceivedMessageTooLarge)
+            } else {
+                cobs::decode_in_place(&mut self.serial[..self.serial_len])
+                    .map_err(|_| WireRxErrorKind::Other)
+            };
+            self.serial_len = 0;
+            self.serial_overflow = false;
+            return Some(result);
+        }
+        None
+    }
+}
+
+/// Receives postcard-rpc frames from either interface
+pub struct AppRx {
+    ep_out: <AppDriver as Driver<'static>>::EndpointOut,
+    serial: Receiver<'static, AppDriver>,
+    state: &'static mut RxState,
+}
+
+impl WireRx for AppRx {
+    type Error = WireRxErrorKind;
+
+    async fn receive<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], Self::Error> {
+        // Waits for the host to configure the device instead of failing straight away
+        self.ep_out.wait_enabled().await;
+
+        loop {
+            //A packet can hold more than one serial frame, those go first
+            if let Some(result) = self.state.next_serial() {
+                let len = result?;
+                let frame = &self.state.serial[..len];
+                with_routes(|routes| routes.request(frame, Interface::Serial));
+                return copy_frame(buf, frame);
+            }
+
+            let mut usb_packet = [0u8; PACKET_SIZE];
+            let state = &mut *self.state;
+            let read = select(
+                self.ep_out.read(&mut usb_packet),
+                self.serial.read_packet(&mut state.packet),
+            )
+            .await;
+            match read {
+                Either::First(read) => {
+                    let n = read.map_err(rx_error)?;
+                    if let Some(result) = state.push_usb(&usb_packet[..n]) {
+                        let len = result?;
+                        let frame = &state.usb[..len];
+                        with_routes(|routes| routes.request(frame, Interface::Usb));
+                        return copy_frame(buf, frame);
+                    }
+                }
+                Either::Second(read) => {
+                    state.packet_len = read.map_err(rx_error)?;
+                    state.packet_pos = 0;
+                }
+            }
+        }
+    }
+}
+
+fn copy_frame<'a>(buf: &'a mut [u8], frame: &[u8]) -> Result<&'a mut [u8], WireRxErrorKind> {
+    let out = buf
+        .get_mut(..frame.len())
+        .ok_or(WireRxErrorKind::ReceivedMessageTooLarge)?;
+    out.copy_from_slice(frame);
+    Ok(out)
+}
+
+fn rx_error(error: EndpointError) -> WireRxErrorKind {
+    match error {
+        EndpointError::BufferOverflow => WireRxErrorKind::ReceivedMessageTooLarge,
+        EndpointError::Disabled => WireRxErrorKind::ConnectionClosed,
+    }
+}
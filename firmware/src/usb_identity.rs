@@ -0,0 +1,3 @@
+//! USB IDs and strings, set at build time through environment variables (see `build.rs`)
+
+include!(concat!(env!("OUT_DIR"), "/usb_identity.rs"));
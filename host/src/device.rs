@@ -0,0 +1,118 @@
+//! The device we are talking to, either through poststation or straight over its serial port
+
+use crate::serial::{self, SerialLink};
+use icd::GetUniqueIdEndpoint;
+use postcard_rpc::{Endpoint, Topic};
+use poststation_sdk::{PoststationClient, StreamListener};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// How to reach the device, set with `--transport`
+#[derive(Clone, Debug)]
+pub enum Transport {
+    /// Through a poststation server on this computer
+    Poststation,
+    /// Straight over the device's serial port, without poststation
+    Serial(PathBuf),
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            _ if s == "poststation" => Ok(Transport::Poststation),
+            Some(("serial", path)) if !path.is_empty() => Ok(Transport::Serial(path.into())),
+            _ => Err("expected poststation or serial:<port>, like serial:/dev/ttyACM0".to_string()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Device {
+    Poststation {
+        client: PoststationClient,
+        serial: u64,
+    },
+    Serial {
+        link: SerialLink,
+        serial: u64,
+    },
+}
+
+impl Device {
+    /// Opens the serial port and asks the device behind it for its serial number
+    pub async fn open_serial(path: &Path) -> Result<Device, String> {
+        let link = SerialLink::open(path)?;
+        let serial = link
+            .call::<GetUniqueIdEndpoint>(&())
+            .await
+            .map_err(|e| format!("No answer from a device on {}: {}", path.display(), e))?;
+        Ok(Device::Serial { link, serial })
+    }
+
+    /// The unique ID of the device, which is also its USB serial number
+    pub fn serial(&self) -> u64 {
+        match self {
+            Device::Poststation { serial, .. } | Device::Serial { serial, .. } => *serial,
+        }
+    }
+
+    /// Sends a request and waits for the reply. Poststation needs a sequence number from us,
+    /// the serial port picks its own
+    pub async fn call<E>(&self, seq_no: u32, request: &E::Request) -> Result<E::Response, String>
+    where
+        E: Endpoint,
+        E::Request: Serialize,
+        E::Response: DeserializeOwned,
+    {
+        match self {
+            Device::Poststation { client, serial } => client
+                .proxy_endpoint::<E>(*serial, seq_no, request)
+                .await
+                .map_err(|e| format!("{:?}", e)),
+            Device::Serial { link, .. } => link.call::<E>(request).await,
+        }
+    }
+
+    /// Receives the messages the device publishes on `T`
+    pub async fn subscribe<T>(&self) -> Result<Subscription<T>, String>
+    where
+        T: Topic,
+        T::Message: DeserializeOwned,
+    {
+        match self {
+            Device::Poststation { client, serial } => client
+                .stream_topic::<T>(*serial)
+                .await
+                .map(Subscription::Poststation)
+                .map_err(|e| format!("{:?}", e)),
+            Device::Serial { link, .. } => Ok(Subscription::Serial(link.subscribe::<T>())),
+        }
+    }
+}
+
+pub enum Subscription<T>
+where
+    T: Topic,
+    T::Message: DeserializeOwned,
+{
+    Poststation(StreamListener<T>),
+    Serial(serial::Subscription<T>),
+}
+
+impl<T> Subscription<T>
+where
+    T: Topic,
+    T::Message: DeserializeOwned,
+{
+    /// The next message, or None once the device is gone
+    pub async fn recv(&mut self) -> Option<T::Message> {
+        match self {
+            Subscription::Poststation(listener) => listener.recv().await,
+            Subscription::Serial(subscription) => subscription.recv().await,
+        }
+    }
+}
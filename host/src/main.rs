@@ -1,37 +1,175 @@
+use chrono::Local;
+use clap::{Args, Parser, Subcommand};
+use crc::{CRC_32_ISO_HDLC, Crc};
+use device::{Device, Transport};
 use dotenv::dotenv;
 use env_logger::Env;
-use icd::{SetDisplayEndpoint, SysInfo};
-use log::{debug, error, info};
-use poststation_sdk::connect;
+use icd::{
+    ConfirmFirmwareEndpoint, DEVICE_NAME_LEN, DeviceName, GetDisplayStatusEndpoint,
+    GetLastPanicEndpoint, GetNameEndpoint, GetSettingsEndpoint, Icon, Layout, LogLevel, LogTopic,
+    Metric, NOTIFICATION_TEXT_LEN, Notification, NotifyEndpoint, Priority, QR_CAPTION_LEN,
+    QR_TEXT_LEN, QrCode, Rgb, SelfTestEndpoint, SetDisplayEndpoint, SetLogLevelEndpoint,
+    SetNameEndpoint, SetSettingsEndpoint, SetTimeEndpoint, SettingsChangedTopic,
+    ShowMetricEndpoint, ShowQrCodeEndpoint, SysInfo, Theme, TimeSync,
+};
+use log::{Level, debug, error, info, log, warn};
+use poststation_sdk::{PoststationClient, connect};
 use std::env;
-use std::time::Duration;
+use std::net::{IpAddr, UdpSocket};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use sysinfo::System;
 use tokio::process::Command;
 use tokio::signal;
 use tokio::time::{interval, sleep};
 
+mod device;
+mod serial;
+mod update;
+
+/// The device counts time on its own, this only corrects its drift
+const TIME_SYNC_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Shows your computer's usage on a Pico running the pc-usage-monitor firmware
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Use the device with this name (see set-name) instead of the first one connected.
+    /// Can also be set with the DEVICE_NAME env variable
+    #[arg(long, short)]
+    device: Option<String>,
+    /// How to reach the device: poststation, or serial:<port> to skip poststation and talk to
+    /// the device's serial port, like serial:/dev/ttyACM0
+    #[arg(long, default_value = "poststation")]
+    transport: Transport,
+    /// Tells this computer's stats apart from others sent to the same device. Can also be set
+    /// with the STATS_SOURCE env variable, by default it is picked from the host name
+    #[arg(long)]
+    source: Option<u8>,
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Sends a new firmware to the device. It needs the bootloader from the bootloader folder
+    Update {
+        /// The firmware as built by cargo (ELF) or a raw binary starting at the application
+        firmware: PathBuf,
+    },
+    /// Scans the device's I2C bus and shows a test pattern on its display
+    SelfTest,
+    /// Gives the device a name to tell it apart from others, shown on its boot screen
+    SetName {
+        /// Up to 20 characters, an empty name clears it
+        name: String,
+    },
+    /// Shows a short message on the device, like "Build finished"
+    Notify {
+        /// Up to 64 characters
+        text: String,
+        /// low or normal show a banner over the current page, high takes the whole screen
+        #[arg(long, default_value = "normal", value_parser = parse_priority)]
+        priority: Priority,
+        /// info, success, warning or error
+        #[arg(long, value_parser = parse_icon)]
+        icon: Option<Icon>,
+        /// How many seconds it stays on screen
+        #[arg(long, default_value_t = 5)]
+        duration: u32,
+    },
+    /// Shows a QR code on the device, by default with this computer's address on the local network
+    Qr {
+        /// Up to 64 characters, like a URL
+        text: Option<String>,
+        /// Shows http://<local address>:<port> instead of the bare address
+        #[arg(long, conflicts_with = "text")]
+        port: Option<u16>,
+        /// Shown under the code, up to 21 characters. Defaults to the text if that fits
+        #[arg(long)]
+        caption: Option<String>,
+        /// How many seconds it stays on screen
+        #[arg(long, default_value_t = 60)]
+        duration: u32,
+    },
+    /// Changes the colors used on color displays. Colors are given like #FF8000, the ones left
+    /// out stay as they are
+    Theme(ThemeChanges),
+    /// Picks how the stats are laid out on the device
+    Layout {
+        /// pages, large-digits for one metric readable across the room, or gauges
+        #[arg(value_parser = parse_layout)]
+        layout: Layout,
+        /// Whether the gauges mark the highest usage of the last few seconds
+        #[arg(long)]
+        peak_hold: Option<bool>,
+    },
+    /// Picks the metric shown in large digits
+    Metric {
+        /// cpu or memory, left out it moves on to the next one
+        #[arg(value_parser = parse_metric)]
+        metric: Option<Metric>,
+    },
+}
+
+/// The colors given to the theme command, `None` where left out
+#[derive(Args)]
+struct ThemeChanges {
+    /// Goes back to the default colors before applying the others
+    #[arg(long)]
+    default: bool,
+    #[arg(long, value_parser = parse_color)]
+    background: Option<Rgb>,
+    #[arg(long, value_parser = parse_color)]
+    text: Option<Rgb>,
+    /// Behind banners and the alert marker
+    #[arg(long, value_parser = parse_color)]
+    highlight: Option<Rgb>,
+    /// Bars below 60%
+    #[arg(long, value_parser = parse_color)]
+    good: Option<Rgb>,
+    /// Bars from 60%
+    #[arg(long, value_parser = parse_color)]
+    busy: Option<Rgb>,
+    /// Bars from 85%
+    #[arg(long, value_parser = parse_color)]
+    critical: Option<Rgb>,
+}
+
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
     //Sets up the logger. Can set RUST_LOG=debug as env variable to see more detailed logs
     let env = Env::default().filter_or("RUST_LOG", "info");
     env_logger::init_from_env(env);
     //Loads in the .env file variables
     dotenv().ok();
 
+    if let Transport::Serial(_) = cli.transport {
+        //Poststation is not needed to talk over the serial port
+        if let Err(e) = run(cli).await {
+            error!("{:?}", e);
+        }
+        return;
+    }
+
     match spawn_poststation().await {
         Some(mut poststation_process) => {
-            //Launch the actual logic of the program in a separate thread
-            tokio::spawn(async move {
+            //Launch the actual logic of the program next to the signal handler
+            let work = async {
                 //Gives time for poststation to start up. May not be needed
                 sleep(Duration::from_millis(500)).await;
-                let result = do_work().await;
-                if let Err(e) = result {
-                    error!("{:?}", e);
-                }
-            });
+                run(cli).await
+            };
 
             // Set up a signal handler to kill poststation process when the parent exits
             tokio::select! {
+                result = work => {
+                    if let Err(e) = result {
+                        error!("{:?}", e);
+                    }
+                    poststation_process.kill().await.expect("Failed to kill Poststation process");
+                }
                 _ = signal::ctrl_c() => {
                     println!("Received Ctrl+C, killing Poststation process...");
                     poststation_process.kill().await.expect("Failed to kill Poststation process");
@@ -47,7 +185,7 @@ async fn main() {
                 "Poststation process did not start, can check above errors for more details. Continuing in case it was launched manually"
             );
             //Launch the actual logic of the program to capture computer usage and display it on the pico
-            let result = do_work().await;
+            let result = run(cli).await;
             if let Err(e) = result {
                 error!("{:?}", e);
             }
@@ -55,8 +193,56 @@ async fn main() {
     }
 }
 
-/// The actual logic of the program to capture computer usage and display it on the pico
-async fn do_work() -> Result<(), String> {
+/// Connects to the chosen device and runs the given command, or the usage monitor without one
+async fn run(cli: Cli) -> Result<(), String> {
+    let name = cli.device.or_else(|| env::var("DEVICE_NAME").ok());
+    let device = match &cli.transport {
+        Transport::Poststation => select_device(name).await?,
+        Transport::Serial(path) => {
+            let device = Device::open_serial(path).await?;
+            info!("Device {:016X} on {}", device.serial(), path.display());
+            if let Some(name) = name {
+                let device_name = device.call::<GetNameEndpoint>(0, &()).await?;
+                if device_name.as_str() != name {
+                    return Err(format!(
+                        "The device on {} is named '{}', not '{}'",
+                        path.display(),
+                        device_name,
+                        name
+                    ));
+                }
+            }
+            device
+        }
+    };
+
+    match cli.command {
+        Some(CliCommand::Update { firmware }) => update::update(device, &firmware).await,
+        Some(CliCommand::SelfTest) => self_test(&device).await,
+        Some(CliCommand::SetName { name }) => set_name(&device, &name).await,
+        Some(CliCommand::Notify {
+            text,
+            priority,
+            icon,
+            duration,
+        }) => notify(&device, &text, priority, icon, duration).await,
+        Some(CliCommand::Qr {
+            text,
+            port,
+            caption,
+            duration,
+        }) => show_qr_code(&device, text, port, caption, duration).await,
+        Some(CliCommand::Theme(changes)) => set_theme(&device, changes).await,
+        Some(CliCommand::Layout { layout, peak_hold }) => {
+            set_layout(&device, layout, peak_hold).await
+        }
+        Some(CliCommand::Metric { metric }) => show_metric(&device, metric).await,
+        None => do_work(device, stats_source(cli.source)?).await,
+    }
+}
+
+/// Connects to poststation and picks the device called `name`, or the first one connected
+async fn select_device(name: Option<String>) -> Result<Device, String> {
     let client = match connect("localhost:51837").await {
         Ok(c) => c,
         Err(e) => {
@@ -75,16 +261,282 @@ async fn do_work() -> Result<(), String> {
         }
     };
 
-    let first_connected_device = connected_devices
-        .iter()
-        .filter(|d| d.is_connected == true)
-        .next()
-        .unwrap_or_else(|| {
-            error!("No connected devices found. Poststation is running, please make sure you have an active device connected");            
-            std::process::exit(1);
-        });
+    let serial = match name {
+        Some(name) => {
+            let serials = connected_devices
+                .iter()
+                .filter(|d| d.is_connected)
+                .map(|d| d.serial);
+            find_device_by_name(&client, serials, &name).await?
+        }
+        None => {
+            let first_connected_device = connected_devices
+                .iter()
+                .find(|d| d.is_connected)
+                .unwrap_or_else(|| {
+                    error!(
+                        "No connected devices found. Poststation is running, please make sure you have an active device connected"
+                    );
+                    std::process::exit(1);
+                });
+            info!("First connected device: {:?}", first_connected_device);
+            first_connected_device.serial
+        }
+    };
+    Ok(Device::Poststation { client, serial })
+}
 
-    info!("First connected device: {:?}", first_connected_device);
+/// Asks every device for its name and returns the serial of the one called `name`
+async fn find_device_by_name(
+    client: &PoststationClient,
+    serials: impl Iterator<Item = u64>,
+    name: &str,
+) -> Result<u64, String> {
+    for serial in serials {
+        match client
+            .proxy_endpoint::<GetNameEndpoint>(serial, 0, &())
+            .await
+        {
+            Ok(device_name) if device_name.as_str() == name => {
+                info!("Using device {:016X} named '{}'", serial, name);
+                return Ok(serial);
+            }
+            Ok(device_name) => debug!("Device {:016X} is named '{}'", serial, device_name),
+            Err(e) => warn!("Could not get the name of device {:016X}: {:?}", serial, e),
+        }
+    }
+    Err(format!("No connected device is named '{}'", name))
+}
+
+async fn set_name(device: &Device, name: &str) -> Result<(), String> {
+    let name = DeviceName::try_from(name).map_err(|_| {
+        format!(
+            "'{}' is too long, names can be up to {} bytes",
+            name, DEVICE_NAME_LEN
+        )
+    })?;
+    device
+        .call::<SetNameEndpoint>(0, &name)
+        .await
+        .map_err(|e| format!("Could not set the device name: {}", e))?;
+    info!("Device {:016X} is now named '{}'", device.serial(), name);
+    Ok(())
+}
+
+async fn notify(
+    device: &Device,
+    text: &str,
+    priority: Priority,
+    icon: Option<Icon>,
+    duration: u32,
+) -> Result<(), String> {
+    let text = text.try_into().map_err(|_| {
+        format!(
+            "'{}' is too long, notifications can be up to {} bytes",
+            text, NOTIFICATION_TEXT_LEN
+        )
+    })?;
+    let notification = Notification {
+        text,
+        priority,
+        icon,
+        duration_ms: duration.saturating_mul(1000),
+    };
+    let queued = device
+        .call::<NotifyEndpoint>(0, &notification)
+        .await
+        .map_err(|e| format!("Could not send the notification: {}", e))?;
+    if !queued {
+        warn!("The device has too many notifications waiting, this one was dropped");
+    }
+    Ok(())
+}
+
+async fn show_qr_code(
+    device: &Device,
+    text: Option<String>,
+    port: Option<u16>,
+    caption: Option<String>,
+    duration: u32,
+) -> Result<(), String> {
+    let text = match (text, port) {
+        (Some(text), _) => text,
+        (None, Some(port)) => format!("http://{}:{}", local_address()?, port),
+        (None, None) => local_address()?.to_string(),
+    };
+    let caption = match caption {
+        Some(caption) => caption.as_str().try_into().map_err(|_| {
+            format!(
+                "'{}' is too long, captions can be up to {} bytes",
+                caption, QR_CAPTION_LEN
+            )
+        })?,
+        //A long URL is still in the code, it just goes without a caption
+        None => text.as_str().try_into().unwrap_or_default(),
+    };
+    let code = QrCode {
+        text: text.as_str().try_into().map_err(|_| {
+            format!(
+                "'{}' is too long, QR codes can hold up to {} bytes",
+                text, QR_TEXT_LEN
+            )
+        })?,
+        caption,
+        duration_ms: duration.saturating_mul(1000),
+    };
+    let shown = device
+        .call::<ShowQrCodeEndpoint>(0, &code)
+        .await
+        .map_err(|e| format!("Could not send the QR code: {}", e))?;
+    if !shown {
+        return Err(format!("'{}' does not fit a QR code on the device", text));
+    }
+    info!("Showing {} as a QR code", text);
+    Ok(())
+}
+
+/// The address other machines on the local network reach this one at. Connecting a UDP socket
+/// sends nothing, it only picks the interface that routes outside
+fn local_address() -> Result<IpAddr, String> {
+    UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+            socket.connect("192.0.2.1:80")?;
+            socket.local_addr()
+        })
+        .map(|address| address.ip())
+        .map_err(|e| format!("Could not find the local network address: {}", e))
+}
+
+/// Reads the settings, changes the theme and sends them back
+async fn set_theme(device: &Device, changes: ThemeChanges) -> Result<(), String> {
+    let mut settings = device
+        .call::<GetSettingsEndpoint>(0, &())
+        .await
+        .map_err(|e| format!("Could not read the device settings: {}", e))?;
+    let theme = &mut settings.theme;
+    if changes.default {
+        *theme = Theme::DEFAULT;
+    }
+    let colors = [
+        (&mut theme.background, changes.background),
+        (&mut theme.text, changes.text),
+        (&mut theme.highlight, changes.highlight),
+        (&mut theme.good, changes.good),
+        (&mut theme.busy, changes.busy),
+        (&mut theme.critical, changes.critical),
+    ];
+    for (color, change) in colors {
+        if let Some(change) = change {
+            *color = change;
+        }
+    }
+    device
+        .call::<SetSettingsEndpoint>(0, &settings)
+        .await
+        .map_err(|e| format!("Could not save the theme: {}", e))?;
+    info!("Theme set to {:?}", settings.theme);
+    Ok(())
+}
+
+async fn set_layout(
+    device: &Device,
+    layout: Layout,
+    peak_hold: Option<bool>,
+) -> Result<(), String> {
+    let mut settings = device
+        .call::<GetSettingsEndpoint>(0, &())
+        .await
+        .map_err(|e| format!("Could not read the device settings: {}", e))?;
+    settings.layout = layout;
+    if let Some(peak_hold) = peak_hold {
+        settings.peak_hold = peak_hold;
+    }
+    device
+        .call::<SetSettingsEndpoint>(0, &settings)
+        .await
+        .map_err(|e| format!("Could not save the layout: {}", e))?;
+    info!("Layout set to {:?}", layout);
+    Ok(())
+}
+
+async fn show_metric(device: &Device, metric: Option<Metric>) -> Result<(), String> {
+    let shown = device
+        .call::<ShowMetricEndpoint>(0, &metric)
+        .await
+        .map_err(|e| format!("Could not change the metric: {}", e))?;
+    info!("Showing {:?} in large digits", shown);
+    let settings = device
+        .call::<GetSettingsEndpoint>(0, &())
+        .await
+        .map_err(|e| format!("Could not read the device settings: {}", e))?;
+    if settings.layout != Layout::LargeDigits {
+        warn!(
+            "The device is not showing large digits, switch with `layout large-digits` to see it"
+        );
+    }
+    Ok(())
+}
+
+/// The `--source` flag, STATS_SOURCE or a number made from the host name, so computers sharing a
+/// device tell themselves apart without setting anything up. Two names can end up on the same
+/// number, one of the two then needs a source set by hand
+fn stats_source(flag: Option<u8>) -> Result<u8, String> {
+    if let Some(source) = flag {
+        return Ok(source);
+    }
+    if let Ok(source) = env::var("STATS_SOURCE") {
+        return source
+            .parse()
+            .map_err(|_| format!("STATS_SOURCE is '{}', expected 0 to 255", source));
+    }
+    let host_name = System::host_name().unwrap_or_default();
+    let source = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(host_name.as_bytes()) as u8;
+    info!("Sending stats as source {} for '{}'", source, host_name);
+    Ok(source)
+}
+
+/// The actual logic of the program to capture computer usage and display it on the pico
+async fn do_work(device: Device, source: u8) -> Result<(), String> {
+    //Keeps a freshly updated firmware, otherwise the bootloader rolls it back
+    if let Err(e) = device.call::<ConfirmFirmwareEndpoint>(0, &()).await {
+        error!("Could not confirm the device firmware: {}", e);
+    }
+    match device.call::<GetSettingsEndpoint>(0, &()).await {
+        Ok(settings) => info!("Device settings: {:?}", settings),
+        Err(e) => error!("Could not read the device settings: {}", e),
+    }
+    match device.call::<GetLastPanicEndpoint>(0, &()).await {
+        Ok(Some(report)) if report.dropped > 0 => error!(
+            "Device {:016X} was reset by a panic: {} ({} more bytes did not fit)",
+            device.serial(),
+            report.message,
+            report.dropped
+        ),
+        Ok(Some(report)) => error!(
+            "Device {:016X} was reset by a panic: {}",
+            device.serial(),
+            report.message
+        ),
+        Ok(None) => debug!("Device did not panic before its last reset"),
+        Err(e) => error!("Could not ask the device for its last panic: {}", e),
+    }
+    if let Ok(level) = env::var("DEVICE_LOG_LEVEL") {
+        match parse_log_level(&level) {
+            Some(level) => {
+                let result = device.call::<SetLogLevelEndpoint>(0, &level).await;
+                if let Err(e) = result {
+                    error!("Could not set the device log level: {}", e);
+                }
+            }
+            None => warn!(
+                "Unknown DEVICE_LOG_LEVEL '{}', expected error, warn, info or debug",
+                level
+            ),
+        }
+    }
+    send_time(&device).await;
+    tokio::spawn(forward_device_logs(device.clone()));
+    tokio::spawn(watch_settings(device.clone()));
 
     let mut sys = System::new_all();
 
@@ -92,6 +544,7 @@ async fn do_work() -> Result<(), String> {
     let host_name = System::host_name().unwrap_or("".to_string());
 
     let mut interval = interval(Duration::from_millis(500));
+    let mut last_time_sync = Instant::now();
 
     loop {
         sys.refresh_cpu_all();
@@ -125,6 +578,7 @@ async fn do_work() -> Result<(), String> {
         };
 
         let sys_info = SysInfo {
+            source,
             host_name: host_name.as_str(),
             cpu_freq_text: cpu_avg_freq_str.as_str(),
             cpu_usage: formatted_to_u8,
@@ -135,22 +589,174 @@ async fn do_work() -> Result<(), String> {
 
         debug!("SysInfo: {:?}", sys_info);
 
-        let result = client
-            .proxy_endpoint::<SetDisplayEndpoint>(
-                first_connected_device.serial,
-                message_seq_number as u32,
-                &sys_info,
-            )
+        let result = device
+            .call::<SetDisplayEndpoint>(message_seq_number as u32, &sys_info)
             .await;
         message_seq_number += 1;
 
         if let Err(e) = result {
-            error!("{:?}", e);
+            error!("{}", e);
+        }
+        if last_time_sync.elapsed() >= TIME_SYNC_INTERVAL {
+            send_time(&device).await;
+            last_time_sync = Instant::now();
         }
         interval.tick().await;
     }
 }
 
+/// Sends our local time so the device can show its clock
+async fn send_time(device: &Device) {
+    let now = Local::now();
+    let time = TimeSync {
+        unix_seconds: now.timestamp(),
+        utc_offset_minutes: (now.offset().local_minus_utc() / 60) as i16,
+    };
+    if let Err(e) = device.call::<SetTimeEndpoint>(0, &time).await {
+        error!("Could not set the device time: {}", e);
+    }
+}
+
+async fn self_test(device: &Device) -> Result<(), String> {
+    info!("Running the self-test, the display should show a checkerboard for two seconds");
+    let report = device
+        .call::<SelfTestEndpoint>(0, &())
+        .await
+        .map_err(|e| format!("Could not run the self-test: {}", e))?;
+
+    if report.i2c_addresses.is_empty() {
+        warn!("Nothing answered on the I2C bus, check the display wiring");
+    }
+    for address in &report.i2c_addresses {
+        info!("I2C device at {:#04X}", address);
+    }
+    if report.display_ready {
+        info!("Display initialized");
+    } else {
+        error!("Display did not initialize");
+    }
+    if report.test_pattern_sent {
+        info!("Test pattern sent to the display");
+    } else {
+        error!("Could not send the test pattern");
+    }
+    match device.call::<GetDisplayStatusEndpoint>(0, &()).await {
+        Ok(status) => {
+            info!(
+                "Display went offline {} times since boot and recovered {} times",
+                status.failures, status.recoveries
+            );
+            info!(
+                "Last frame sent {} bytes in {} µs, the slowest took {} µs",
+                status.last_flush_bytes, status.last_flush_us, status.slowest_flush_us
+            );
+        }
+        Err(e) => error!("Could not get the display status: {}", e),
+    }
+    Ok(())
+}
+
+/// Logs the settings whenever they are changed from the menu on the device
+async fn watch_settings(device: Device) {
+    let mut settings_changes = match device.subscribe::<SettingsChangedTopic>().await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not listen for settings changes: {}", e);
+            return;
+        }
+    };
+    while let Some(settings) = settings_changes.recv().await {
+        info!("Device settings changed: {:?}", settings);
+    }
+}
+
+/// Writes the log records from the device to our own log, prefixed with the device serial
+async fn forward_device_logs(device: Device) {
+    let mut records = match device.subscribe::<LogTopic>().await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not listen for device logs: {}", e);
+            return;
+        }
+    };
+    while let Some(record) = records.recv().await {
+        let level = match record.level {
+            LogLevel::Error => Level::Error,
+            LogLevel::Warn => Level::Warn,
+            LogLevel::Info => Level::Info,
+            LogLevel::Debug => Level::Debug,
+        };
+        log!(
+            target: record.module.as_str(),
+            level,
+            "[{:016X}] {}",
+            device.serial(),
+            record.message
+        );
+    }
+}
+
+fn parse_log_level(level: &str) -> Option<LogLevel> {
+    match level.to_lowercase().as_str() {
+        "error" => Some(LogLevel::Error),
+        "warn" => Some(LogLevel::Warn),
+        "info" => Some(LogLevel::Info),
+        "debug" => Some(LogLevel::Debug),
+        _ => None,
+    }
+}
+
+fn parse_priority(priority: &str) -> Result<Priority, String> {
+    match priority.to_lowercase().as_str() {
+        "low" => Ok(Priority::Low),
+        "normal" => Ok(Priority::Normal),
+        "high" => Ok(Priority::High),
+        _ => Err("expected low, normal or high".to_string()),
+    }
+}
+
+fn parse_icon(icon: &str) -> Result<Icon, String> {
+    match icon.to_lowercase().as_str() {
+        "info" => Ok(Icon::Info),
+        "success" => Ok(Icon::Success),
+        "warning" => Ok(Icon::Warning),
+        "error" => Ok(Icon::Error),
+        _ => Err("expected info, success, warning or error".to_string()),
+    }
+}
+
+fn parse_layout(layout: &str) -> Result<Layout, String> {
+    match layout.to_lowercase().as_str() {
+        "pages" => Ok(Layout::Pages),
+        "large-digits" => Ok(Layout::LargeDigits),
+        "gauges" => Ok(Layout::Gauges),
+        _ => Err("expected pages, large-digits or gauges".to_string()),
+    }
+}
+
+fn parse_metric(metric: &str) -> Result<Metric, String> {
+    match metric.to_lowercase().as_str() {
+        "cpu" => Ok(Metric::Cpu),
+        "memory" => Ok(Metric::Memory),
+        _ => Err("expected cpu or memory".to_string()),
+    }
+}
+
+/// A color like #FF8000, the # is optional
+fn parse_color(color: &str) -> Result<Rgb, String> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    let value = match hex.len() {
+        6 => u32::from_str_radix(hex, 16).ok(),
+        _ => None,
+    };
+    let value = value.ok_or_else(|| "expected a color like #FF8000".to_string())?;
+    Ok(Rgb {
+        r: (value >> 16) as u8,
+        g: (value >> 8) as u8,
+        b: value as u8,
+    })
+}
+
 /// This method spawns poststation in headless mode so we don't have to manually launch it
 /// If you do not have the env set the program will still work, just need to manually start poststation
 async fn spawn_poststation() -> Option<tokio::process::Child> {
@@ -0,0 +1,391 @@
+//! Talks to the device over its CDC-ACM serial port, for when poststation is not running. Every
+//! frame is a postcard-rpc header and body, COBS encoded and ended with a 0. See `transport.rs`
+//! in the firmware for the device side of this.
+
+use log::{debug, warn};
+use postcard_rpc::header::{VarHeader, VarKey, VarSeq};
+use postcard_rpc::standard_icd::{ERROR_KEY, WireError};
+use postcard_rpc::{Endpoint, Topic};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+
+/// The self-test takes a few seconds on the device, everything else is much quicker
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A reply to a request, or a topic message, without its header
+type Body = Vec<u8>;
+
+/// The open serial port, cheap to clone
+#[derive(Clone)]
+pub struct SerialLink {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    path: PathBuf,
+    port: Mutex<File>,
+    next_seq: AtomicU32,
+    /// Requests waiting for their reply, by sequence number
+    pending: Mutex<HashMap<u32, PendingReply>>,
+    topics: Mutex<Vec<(VarKey, mpsc::UnboundedSender<Body>)>>,
+}
+
+struct PendingReply {
+    key: VarKey,
+    reply: oneshot::Sender<Result<Body, String>>,
+}
+
+impl SerialLink {
+    /// Opens the port and starts reading frames from it in the background
+    pub fn open(path: &Path) -> Result<SerialLink, String> {
+        let port = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+        set_raw(&port).map_err(|e| format!("Could not set up {}: {}", path.display(), e))?;
+        let reader = port
+            .try_clone()
+            .map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+
+        let shared = Arc::new(Shared {
+            path: path.to_path_buf(),
+            port: Mutex::new(port),
+            next_seq: AtomicU32::new(0),
+            pending: Mutex::new(HashMap::new()),
+            topics: Mutex::new(Vec::new()),
+        });
+        let reader_shared = shared.clone();
+        std::thread::spawn(move || read_frames(reader, &reader_shared));
+        Ok(SerialLink { shared })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.shared.path
+    }
+
+    /// Sends a request and waits for its reply
+    pub async fn call<E>(&self, request: &E::Request) -> Result<E::Response, String>
+    where
+        E: Endpoint,
+        E::Request: Serialize,
+        E::Response: DeserializeOwned,
+    {
+        let seq = self.shared.next_seq.fetch_add(1, Ordering::Relaxed);
+        let (reply, replied) = oneshot::channel();
+        self.shared.pending.lock().unwrap().insert(
+            seq,
+            PendingReply {
+                key: VarKey::Key8(E::RESP_KEY),
+                reply,
+            },
+        );
+
+        let header = VarHeader {
+            key: VarKey::Key8(E::REQ_KEY),
+            seq_no: VarSeq::Seq4(seq),
+        };
+        let mut frame = header.write_to_vec();
+        frame.extend(
+            postcard::to_stdvec(request)
+                .map_err(|e| format!("Could not serialize the request: {}", e))?,
+        );
+        if let Err(e) = self.send_frame(&frame) {
+            self.shared.pending.lock().unwrap().remove(&seq);
+            return Err(e);
+        }
+
+        let body = match timeout(REPLY_TIMEOUT, replied).await {
+            Ok(Ok(body)) => body?,
+            Ok(Err(_)) => return Err(format!("{} was closed", self.path().display())),
+            Err(_) => {
+                self.shared.pending.lock().unwrap().remove(&seq);
+                return Err(format!("No reply to {} from the device", E::PATH));
+            }
+        };
+        postcard::from_bytes(&body).map_err(|e| format!("Could not read the reply: {}", e))
+    }
+
+    /// Receives every message the device publishes on `T`
+    pub fn subscribe<T>(&self) -> Subscription<T>
+    where
+        T: Topic,
+        T::Message: DeserializeOwned,
+    {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.shared
+            .topics
+            .lock()
+            .unwrap()
+            .push((VarKey::Key8(T::TOPIC_KEY), sender));
+        Subscription {
+            receiver,
+            _topic: PhantomData,
+        }
+    }
+
+    fn send_frame(&self, frame: &[u8]) -> Result<(), String> {
+        let mut encoded = cobs::encode_vec(frame);
+        encoded.push(0);
+        let mut port = self.shared.port.lock().unwrap();
+        port.write_all(&encoded)
+            .and_then(|_| port.flush())
+            .map_err(|e| format!("Could not write to {}: {}", self.path().display(), e))
+    }
+}
+
+/// Messages published on a topic, see [`SerialLink::subscribe`]
+pub struct Subscription<T> {
+    receiver: mpsc::UnboundedReceiver<Body>,
+    _topic: PhantomData<fn() -> T>,
+}
+
+impl<T> Subscription<T>
+where
+    T: Topic,
+    T::Message: DeserializeOwned,
+{
+    /// The next message, or None once the port is closed
+    pub async fn recv(&mut self) -> Option<T::Message> {
+        while let Some(body) = self.receiver.recv().await {
+            match postcard::from_bytes(&body) {
+                Ok(message) => return Some(message),
+                Err(e) => warn!("Could not read a message on {}: {}", T::PATH, e),
+            }
+        }
+        None
+    }
+}
+
+/// Splits what comes in on the port at each 0 and hands the frames out until the port closes
+fn read_frames(mut port: File, shared: &Shared) {
+    let mut buf = [0u8; 256];
+    let mut frame = Vec::new();
+    loop {
+        let n = match port.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                debug!("Reading {} failed: {}", shared.path.display(), e);
+                break;
+            }
+        };
+        for &byte in &buf[..n] {
+            if byte != 0 {
+                frame.push(byte);
+            } else if !frame.is_empty() {
+                handle_frame(shared, &mut frame);
+                frame.clear();
+            }
+        }
+    }
+
+    warn!("Lost the connection on {}", shared.path.display());
+    //Dropping the senders wakes up everyone still waiting
+    shared.pending.lock().unwrap().clear();
+    shared.topics.lock().unwrap().clear();
+}
+
+fn handle_frame(shared: &Shared, frame: &mut [u8]) {
+    let Ok(len) = cobs::decode_in_place(frame) else {
+        warn!("Dropped a frame that is not valid COBS");
+        return;
+    };
+    let Some((header, body)) = VarHeader::take_from_slice(&frame[..len]) else {
+        warn!("Dropped a frame without a valid header");
+        return;
+    };
+
+    let seq = match header.seq_no {
+        VarSeq::Seq1(seq) => seq as u32,
+        VarSeq::Seq2(seq) => seq as u32,
+        VarSeq::Seq4(seq) => seq,
+    };
+    let is_error = header.key == VarKey::Key8(ERROR_KEY);
+    //Topics have their own sequence numbers, so a reply has to match the key as well
+    if let Entry::Occupied(entry) = shared.pending.lock().unwrap().entry(seq)
+        && (is_error || entry.get().key == header.key)
+    {
+        let result = if is_error {
+            match postcard::from_bytes::<WireError>(body) {
+                Ok(e) => Err(format!("The device could not handle the request: {:?}", e)),
+                Err(_) => Err("The device could not handle the request".to_string()),
+            }
+        } else {
+            Ok(body.to_vec())
+        };
+        let _ = entry.remove().reply.send(result);
+        return;
+    }
+
+    shared
+        .topics
+        .lock()
+        .unwrap()
+        .retain(|(key, sender)| *key != header.key || sender.send(body.to_vec()).is_ok());
+}
+
+/// Turns off everything the terminal driver would do to the bytes, like echo and line endings
+#[cfg(unix)]
+fn set_raw(port: &File) -> std::io::Result<()> {
+    use std::mem::MaybeUninit;
+    use std::os::fd::AsRawFd;
+
+    let fd = port.as_raw_fd();
+    let mut termios = MaybeUninit::<libc::termios>::uninit();
+    // SAFETY: tcgetattr fills in the struct when it succeeds, which is checked before using it
+    let termios = unsafe {
+        if libc::tcgetattr(fd, termios.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut termios = termios.assume_init();
+        libc::cfmakeraw(&mut termios);
+        termios
+    };
+    // SAFETY: fd is open for as long as `port` is and termios came from tcgetattr
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// COM ports need their timeouts set up before reads return early, which is not done yet
+#[cfg(not(unix))]
+fn set_raw(_port: &File) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "the serial transport only works on Linux and macOS for now",
+    ))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use icd::{DeviceSettings, GetUniqueIdEndpoint, SettingsChangedTopic};
+    use std::ffi::CStr;
+    use std::os::fd::FromRawFd;
+
+    /// A pty pair, the test plays the device on the master and hands the slave to [`SerialLink`]
+    fn pty_pair() -> (File, PathBuf, File) {
+        let (mut master, mut slave) = (0, 0);
+        let mut name = [0 as libc::c_char; 64];
+        // SAFETY: both fds are written by openpty and only used when it succeeds
+        let opened = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(opened, 0, "openpty failed");
+        // SAFETY: name is long enough for a pty path and ttyname_r terminates it
+        let named = unsafe { libc::ttyname_r(slave, name.as_mut_ptr(), name.len()) };
+        assert_eq!(named, 0, "ttyname_r failed");
+        // SAFETY: ttyname_r succeeded, so name holds a terminated string
+        let path = unsafe { CStr::from_ptr(name.as_ptr()) }
+            .to_str()
+            .unwrap()
+            .into();
+        // SAFETY: openpty handed over both fds and nothing else owns them
+        unsafe { (File::from_raw_fd(master), path, File::from_raw_fd(slave)) }
+    }
+
+    /// Reads the next frame the host sent, without its COBS encoding
+    fn read_frame(master: &mut File) -> (VarHeader, Vec<u8>) {
+        let mut frame = Vec::new();
+        let mut byte = [0u8];
+        loop {
+            master.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                0 if !frame.is_empty() => break,
+                0 => {}
+                b => frame.push(b),
+            }
+        }
+        let len = cobs::decode_in_place(&mut frame).unwrap();
+        let (header, body) = VarHeader::take_from_slice(&frame[..len]).unwrap();
+        (header, body.to_vec())
+    }
+
+    fn write_frame(master: &mut File, key: VarKey, seq_no: VarSeq, body: &impl Serialize) {
+        let mut frame = VarHeader { key, seq_no }.write_to_vec();
+        frame.extend(postcard::to_stdvec(body).unwrap());
+        let mut encoded = cobs::encode_vec(&frame);
+        encoded.push(0);
+        master.write_all(&encoded).unwrap();
+    }
+
+    #[tokio::test]
+    async fn call_gets_its_reply_and_topics_reach_subscribers() {
+        let (mut master, path, _slave) = pty_pair();
+        let link = SerialLink::open(&path).unwrap();
+        let mut settings = link.subscribe::<SettingsChangedTopic>();
+
+        let device = std::thread::spawn(move || {
+            let (header, body) = read_frame(&mut master);
+            assert_eq!(header.key, VarKey::Key8(GetUniqueIdEndpoint::REQ_KEY));
+            assert!(body.is_empty());
+            let pushed = DeviceSettings {
+                peak_hold: false,
+                ..Default::default()
+            };
+            write_frame(
+                &mut master,
+                VarKey::Key8(SettingsChangedTopic::TOPIC_KEY),
+                VarSeq::Seq2(0),
+                &pushed,
+            );
+            write_frame(
+                &mut master,
+                VarKey::Key8(GetUniqueIdEndpoint::RESP_KEY),
+                header.seq_no,
+                &0x1234_u64,
+            );
+            master
+        });
+
+        let unique_id = link.call::<GetUniqueIdEndpoint>(&()).await.unwrap();
+        assert_eq!(unique_id, 0x1234);
+        let pushed = timeout(Duration::from_secs(1), settings.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!pushed.peak_hold);
+        drop(device.join().unwrap());
+    }
+
+    #[tokio::test]
+    async fn reply_with_another_seq_is_not_taken() {
+        let (mut master, path, _slave) = pty_pair();
+        let link = SerialLink::open(&path).unwrap();
+
+        let device = std::thread::spawn(move || {
+            let (header, _) = read_frame(&mut master);
+            let VarSeq::Seq4(seq) = header.seq_no else {
+                panic!("expected a 4 byte seq, got {:?}", header.seq_no);
+            };
+            let key = VarKey::Key8(GetUniqueIdEndpoint::RESP_KEY);
+            write_frame(&mut master, key, VarSeq::Seq4(seq + 1), &1_u64);
+            write_frame(&mut master, key, VarSeq::Seq4(seq), &2_u64);
+            master
+        });
+
+        let unique_id = link.call::<GetUniqueIdEndpoint>(&()).await.unwrap();
+        assert_eq!(unique_id, 2);
+        drop(device.join().unwrap());
+    }
+}
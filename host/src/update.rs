@@ -0,0 +1,170 @@
+//! Pushes a new firmware to the device over postcard-rpc. See `ota.rs` in the firmware for the
+//! device side of this.
+
+use crate::device::Device;
+use crc::{CRC_32_ISO_HDLC, Crc};
+use icd::{
+    ConfirmFirmwareEndpoint, OtaBegin, OtaBeginEndpoint, OtaChunk, OtaFinishEndpoint, OtaResult,
+    OtaWriteEndpoint,
+};
+use log::{debug, info};
+use object::Endianness;
+use object::elf::{ELFMAG, PT_LOAD};
+use object::read::elf::{ElfFile32, ProgramHeader};
+use poststation_sdk::PoststationClient;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Where the firmware runs from behind the bootloader, this has to match firmware/memory.x
+const APP_FLASH_START: u32 = 0x1000_A000;
+/// Bytes of firmware per request, leaves plenty of room in the device's 1K receive buffer
+const CHUNK_SIZE: usize = 512;
+/// The device has to restart and the bootloader swap the images within this time
+const RESTART_TIMEOUT: Duration = Duration::from_secs(90);
+const NOT_BACK: &str = "The device did not come back after the update. If it does later, the \
+    bootloader will roll back to the old firmware unless the monitor is started";
+
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Sends the firmware at `path` (an ELF or a raw binary), then waits for the device to come back
+/// up with it and confirms it so the bootloader does not roll back
+pub async fn update(device: Device, path: &Path) -> Result<(), String> {
+    let file =
+        std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let image = if file.starts_with(&ELFMAG) {
+        elf_to_binary(&file)?
+    } else {
+        file
+    };
+    let crc32 = CRC.checksum(&image);
+    info!(
+        "Sending {} bytes of firmware to {:016X}, CRC {:08X}",
+        image.len(),
+        device.serial(),
+        crc32
+    );
+
+    let begin = OtaBegin {
+        size: image.len() as u32,
+        crc32,
+    };
+    let result = device.call::<OtaBeginEndpoint>(0, &begin).await;
+    check("begin", result)?;
+
+    let chunk_count = image.len().div_ceil(CHUNK_SIZE);
+    for (index, data) in image.chunks(CHUNK_SIZE).enumerate() {
+        let chunk = OtaChunk {
+            offset: (index * CHUNK_SIZE) as u32,
+            data,
+        };
+        let result = device
+            .call::<OtaWriteEndpoint>(index as u32 + 1, &chunk)
+            .await;
+        check("write", result)?;
+        debug!("Sent chunk {}/{}", index + 1, chunk_count);
+        if (index + 1) % 128 == 0 {
+            info!("{}%", (index + 1) * 100 / chunk_count);
+        }
+    }
+
+    let result = device.call::<OtaFinishEndpoint>(0, &()).await;
+    check("finish", result)?;
+    info!("Firmware sent, waiting for the device to restart with it");
+
+    let device = match device {
+        Device::Poststation { client, serial } => {
+            wait_for_restart(&client, serial).await?;
+            Device::Poststation { client, serial }
+        }
+        Device::Serial { link, .. } => reopen_serial(link.path()).await?,
+    };
+    device
+        .call::<ConfirmFirmwareEndpoint>(0, &())
+        .await
+        .map_err(|e| format!("Could not confirm the new firmware: {}", e))?;
+    info!(
+        "Device {:016X} is running the new firmware",
+        device.serial()
+    );
+    Ok(())
+}
+
+fn check(step: &str, result: Result<OtaResult, String>) -> Result<(), String> {
+    match result {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(format!("Device rejected the update at {}: {:?}", step, e)),
+        Err(e) => Err(format!("Could not send the update at {}: {}", step, e)),
+    }
+}
+
+/// Waits for the device to drop off and then show up again
+async fn wait_for_restart(client: &PoststationClient, serial: u64) -> Result<(), String> {
+    let start = Instant::now();
+    let mut seen_disconnected = false;
+    while start.elapsed() < RESTART_TIMEOUT {
+        sleep(Duration::from_millis(500)).await;
+        let connected = match client.get_devices().await {
+            Ok(devices) => devices.iter().any(|d| d.serial == serial && d.is_connected),
+            Err(e) => return Err(format!("Could not get the connected devices: {:?}", e)),
+        };
+        if !connected {
+            seen_disconnected = true;
+        } else if seen_disconnected {
+            return Ok(());
+        }
+    }
+    Err(NOT_BACK.to_string())
+}
+
+/// Waits for the serial port to go away while the device restarts, then opens it again
+async fn reopen_serial(path: &Path) -> Result<Device, String> {
+    let start = Instant::now();
+    let mut seen_disconnected = false;
+    while start.elapsed() < RESTART_TIMEOUT {
+        sleep(Duration::from_millis(500)).await;
+        if !path.exists() {
+            seen_disconnected = true;
+        } else if seen_disconnected {
+            //The port can show up a moment before it can be opened
+            match Device::open_serial(path).await {
+                Ok(device) => return Ok(device),
+                Err(e) => debug!("{}", e),
+            }
+        }
+    }
+    Err(NOT_BACK.to_string())
+}
+
+/// Lays the loadable segments of the ELF out the way they end up in flash
+fn elf_to_binary(file: &[u8]) -> Result<Vec<u8>, String> {
+    let elf = ElfFile32::<Endianness>::parse(file)
+        .map_err(|e| format!("Could not parse the ELF file: {}", e))?;
+    let endian = elf.endian();
+
+    let mut image = Vec::new();
+    for segment in elf.elf_program_headers() {
+        if segment.p_type(endian) != PT_LOAD || segment.p_filesz(endian) == 0 {
+            continue;
+        }
+        let address = segment.p_paddr(endian);
+        let start = address.checked_sub(APP_FLASH_START).ok_or_else(|| {
+            format!(
+                "Segment at {:#010X} is before the application, was the firmware built for the bootloader?",
+                address
+            )
+        })? as usize;
+        let data = segment
+            .data(endian, file)
+            .map_err(|_| "Could not read a segment of the ELF file".to_string())?;
+        if image.len() < start + data.len() {
+            image.resize(start + data.len(), 0xFF);
+        }
+        image[start..start + data.len()].copy_from_slice(data);
+    }
+
+    if image.is_empty() {
+        return Err("The ELF file has nothing to flash".to_string());
+    }
+    Ok(image)
+}
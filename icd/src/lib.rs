@@ -1,5 +1,8 @@
 #![cfg_attr(not(feature = "use-std"), no_std)]
 
+pub mod settings_record;
+
+use heapless::{String, Vec};
 use postcard_rpc::{endpoints, topics, TopicDirection};
 use postcard_schema::Schema;
 use serde::{Deserialize, Serialize};
@@ -14,14 +17,145 @@ pub struct SleptMillis {
     pub millis: u16,
 }
 
-#[derive(Debug, Serialize, Deserialize, Schema)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
 pub enum LedState {
     Off,
     On,
+    /// Constantly on, from 0 (off) to 100 (full brightness)
+    Brightness(u8),
+    Blink { on_ms: u16, off_ms: u16 },
+    /// Two short flashes, then a pause
+    Heartbeat,
+    /// Slowly fades in and out, once every `period_ms`
+    Breathing { period_ms: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+/// What the WS2812 status LED shows
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum RgbEffect {
+    /// Green to red following CPU usage, pulsing red while a metric is over its alert threshold
+    Status,
+    Solid(Rgb),
+    /// Fades the color in and out, once every `period_ms`
+    Pulse { color: Rgb, period_ms: u16 },
+    /// A blend between `low` and `high`, where `value` goes from 0 (all `low`) to 100 (all `high`)
+    Gradient { low: Rgb, high: Rgb, value: u8 },
+}
+
+/// A tone for the piezo buzzer, played `repeat` times with `pause_ms` of silence after each.
+/// The device answers `false` if the buzzer is muted or too many tones are already queued
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct PlayTone {
+    pub frequency_hz: u16,
+    pub duration_ms: u16,
+    pub pause_ms: u16,
+    pub repeat: u8,
+}
+
+/// Longest panic message the device keeps, anything after this is cut off
+pub const PANIC_MESSAGE_LEN: usize = 192;
+
+/// The panic that caused the last reset of the device
+#[derive(Debug, Clone, Serialize, Deserialize, Schema)]
+pub struct PanicReport {
+    pub message: String<PANIC_MESSAGE_LEN>,
+    /// Bytes of the message cut off to fit, the end of `message` is marked when this is not 0
+    pub dropped: u32,
+}
+
+/// `None` if the device was not reset by a panic
+pub type LastPanic = Option<PanicReport>;
+
+/// Ordered from most to least important, the device sends everything at or above its level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Schema)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+/// A log line from the device, `module` is the path of the module that logged it
+#[derive(Debug, Clone, Serialize, Deserialize, Schema)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    pub module: String<48>,
+    pub message: String<128>,
+}
+
+/// Starts a firmware update, `crc32` (ISO-HDLC) covers the whole `size` bytes of the image
+#[derive(Debug, Serialize, Deserialize, Schema)]
+pub struct OtaBegin {
+    pub size: u32,
+    pub crc32: u32,
+}
+
+/// A piece of the firmware image, chunks have to be sent in order
+#[derive(Debug, Serialize, Deserialize, Schema)]
+pub struct OtaChunk<'a> {
+    pub offset: u32,
+    pub data: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum OtaError {
+    /// No update was started with the begin endpoint
+    NotStarted,
+    /// The image does not fit in the update partition
+    TooLarge,
+    /// A chunk did not start where the last one ended
+    OutOfOrder { expected: u32 },
+    /// Fewer bytes were received than announced
+    Incomplete,
+    /// Writing to or reading from flash failed
+    Flash,
+    /// What ended up in flash does not match the CRC from the begin endpoint
+    CrcMismatch,
+}
+
+pub type OtaResult = Result<(), OtaError>;
+
+/// How the display has been doing since boot
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct DisplayStatus {
+    /// False while the display is not responding and being re-initialized
+    pub ready: bool,
+    /// Times the display was found not responding
+    pub failures: u32,
+    /// Times it came back after being re-initialized
+    pub recoveries: u32,
+    /// Only the parts of the screen that changed are sent. Bytes of pixels in the last frame
+    /// that changed anything, out of 1024 for the whole SSD1306 and more on color displays
+    pub last_flush_bytes: u32,
+    /// How long sending that frame took, in microseconds
+    pub last_flush_us: u32,
+    /// The slowest frame since boot, in microseconds
+    pub slowest_flush_us: u32,
+}
+
+/// What the hardware self-test found, for when the screen stays dark
+#[derive(Debug, Clone, Serialize, Deserialize, Schema)]
+pub struct SelfTestReport {
+    /// 7 bit addresses that answered on the display's I2C bus. The SSD1306 is usually 0x3C
+    pub i2c_addresses: Vec<u8, 16>,
+    /// Whether the display has been initialized successfully
+    pub display_ready: bool,
+    /// Whether the test pattern made it to the display
+    pub test_pattern_sent: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Schema)]
 pub struct SysInfo<'a> {
+    /// Which host this is from. Hosts sharing a device need different ones, the device keeps
+    /// the last stats from each
+    pub source: u8,
     pub host_name: &'a str,
     pub cpu_freq_text: &'a str,
     pub cpu_usage: u8,
@@ -30,6 +164,226 @@ pub struct SysInfo<'a> {
     pub scroll_text: &'a str,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum Brightness {
+    Dimmest,
+    Dim,
+    Normal,
+    Brighter,
+    Brightest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum Rotation {
+    Normal,
+    /// Rotated 180 degrees, for when the screen is mounted upside down
+    Flipped,
+}
+
+/// The pages the device can show, switched between with the rotary encoder
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum Page {
+    Overview,
+    Cpu,
+    Memory,
+    /// Local time, once the host has sent it with [`SetTimeEndpoint`]
+    Clock,
+}
+
+/// Clock of the I2C bus the display is on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum I2cSpeed {
+    /// 100 kHz, the safest with long wires
+    Standard,
+    /// 400 kHz, the most the SSD1306 is rated for
+    Fast,
+    /// 1 MHz, past the SSD1306's rating but most modules keep up
+    FastPlus,
+}
+
+/// How the stats are shown when more than one host is sending them
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum MultiHostLayout {
+    /// The pages show one host at a time, switching to the next every few seconds
+    Rotate,
+    /// A column per host with its CPU and memory usage, instead of the pages
+    SideBySide,
+}
+
+/// How the stats are laid out on the display
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum Layout {
+    /// The pages in `page_order`, switched between with the rotary encoder
+    Pages,
+    /// One metric in digits large enough to read across the room. The rotary encoder or
+    /// [`ShowMetricEndpoint`] switch between the metrics
+    LargeDigits,
+    /// A half circle gauge each for CPU and memory
+    Gauges,
+}
+
+/// A metric [`Layout::LargeDigits`] can show
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum Metric {
+    Cpu,
+    Memory,
+}
+
+/// The metric for [`ShowMetricEndpoint`] to show, `None` for the next one. It answers with the
+/// one now shown
+pub type MetricChoice = Option<Metric>;
+
+/// Colors the pages are drawn in on color displays. The SSD1306 ignores it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct Theme {
+    pub background: Rgb,
+    pub text: Rgb,
+    /// Behind banners, the alert marker and other inverted text
+    pub highlight: Rgb,
+    /// Bars below 60%
+    pub good: Rgb,
+    /// Bars from 60%
+    pub busy: Rgb,
+    /// Bars from 85%
+    pub critical: Rgb,
+}
+
+impl Theme {
+    pub const DEFAULT: Self = Self {
+        background: Rgb { r: 0, g: 0, b: 0 },
+        text: Rgb { r: 255, g: 255, b: 255 },
+        highlight: Rgb { r: 0, g: 120, b: 215 },
+        good: Rgb { r: 0, g: 200, b: 0 },
+        busy: Rgb { r: 255, g: 200, b: 0 },
+        critical: Rgb { r: 230, g: 0, b: 0 },
+    };
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Settings that can be changed from the on-device menu or by the host.
+/// These are persisted to flash on the device, see [`settings_record`].
+///
+/// New fields only ever go at the end. Every new field must bump [`settings_record::VERSION`] and
+/// add an arm for the new version to `append_new_fields`, otherwise saved settings are lost on
+/// the next firmware update
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct DeviceSettings {
+    pub brightness: Brightness,
+    pub rotation: Rotation,
+    pub page_order: [Page; 3],
+    /// CPU usage percentage at which the alert is shown. 0 disables it
+    pub cpu_alert: u8,
+    /// Memory usage percentage at which the alert is shown. 0 disables it
+    pub memory_alert: u8,
+    /// Silences the buzzer, both for alerts and tones played by the host
+    pub buzzer_muted: bool,
+    /// Friendly name to tell devices apart, empty if none was set
+    pub name: DeviceName,
+    /// Shows the clock after the pages in `page_order`. It is also shown when the host stops
+    /// sending stats, if the time is known
+    pub show_clock: bool,
+    pub multi_host: MultiHostLayout,
+    pub i2c_speed: I2cSpeed,
+    /// Only set by the host, the menu has no way to pick colors
+    pub theme: Theme,
+    pub layout: Layout,
+    /// Marks the highest usage of the last few seconds on the gauges. Only set by the host
+    pub peak_hold: bool,
+}
+
+impl DeviceSettings {
+    pub const DEFAULT: Self = Self {
+        brightness: Brightness::Normal,
+        rotation: Rotation::Normal,
+        page_order: [Page::Overview, Page::Cpu, Page::Memory],
+        cpu_alert: 90,
+        memory_alert: 90,
+        buzzer_muted: false,
+        name: String::new(),
+        show_clock: true,
+        multi_host: MultiHostLayout::Rotate,
+        i2c_speed: I2cSpeed::Standard,
+        theme: Theme::DEFAULT,
+        layout: Layout::Pages,
+        peak_hold: true,
+    };
+}
+
+impl Default for DeviceSettings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Longest device name, one line on the boot screen
+pub const DEVICE_NAME_LEN: usize = 20;
+
+/// A name like "left-monitor", set through [`SetNameEndpoint`] and kept in flash
+pub type DeviceName = String<DEVICE_NAME_LEN>;
+
+/// Longest notification text, four lines on the full screen
+pub const NOTIFICATION_TEXT_LEN: usize = 64;
+
+/// How a notification is shown, and which goes first when several are queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Schema)]
+pub enum Priority {
+    /// A banner across the top of the current page
+    Low,
+    /// A banner like `Low`, shown before any `Low` ones
+    Normal,
+    /// Takes over the whole screen
+    High,
+}
+
+/// Small pictures shown next to the notification text
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum Icon {
+    Info,
+    Success,
+    Warning,
+    Error,
+}
+
+/// A short message like "Build finished" to show on the device for a while.
+/// The device answers `false` if its queue is full of notifications at least as important
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct Notification {
+    pub text: String<NOTIFICATION_TEXT_LEN>,
+    pub priority: Priority,
+    pub icon: Option<Icon>,
+    /// How long it stays up once shown, the stats come back after that
+    pub duration_ms: u32,
+}
+
+/// Longest text a QR code on the device can hold, enough for a URL with a LAN address
+pub const QR_TEXT_LEN: usize = 64;
+/// Longest caption under a QR code, one line in the small font
+pub const QR_CAPTION_LEN: usize = 21;
+
+/// Something to hand over to a phone, like "http://192.168.1.20:8080", shown as a QR code over
+/// the whole screen. The device answers `false` if the text does not fit a code it can show
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct QrCode {
+    pub text: String<QR_TEXT_LEN>,
+    pub caption: String<QR_CAPTION_LEN>,
+    /// How long it stays up, the stats come back after that
+    pub duration_ms: u32,
+}
+
+/// Wall-clock time from the host. The device counts on from here until the next one
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct TimeSync {
+    /// Seconds since 1970-01-01 00:00 UTC
+    pub unix_seconds: i64,
+    /// Local time minus UTC, so the device can show local time
+    pub utc_offset_minutes: i16,
+}
+
 // ---
 
 // Endpoints spoken by our device
@@ -45,6 +399,26 @@ endpoints! {
     | SetLedEndpoint            | LedState      | ()                    | "template/led/set"            |
     | GetLedEndpoint            | ()            | LedState              | "template/led/get"            |
     | SetDisplayEndpoint        | SysInfo<'a>       | ()                | "template/display/set"        |
+    | GetSettingsEndpoint       | ()            | DeviceSettings        | "template/settings/get"       |
+    | SetSettingsEndpoint       | DeviceSettings | ()                   | "template/settings/set"       |
+    | SetRgbLedEndpoint         | RgbEffect     | ()                    | "template/rgb/set"            |
+    | GetRgbLedEndpoint         | ()            | RgbEffect             | "template/rgb/get"            |
+    | PlayToneEndpoint          | PlayTone      | bool                  | "template/buzzer/play"        |
+    | GetLastPanicEndpoint      | ()            | LastPanic             | "template/panic/get"          |
+    | SetLogLevelEndpoint       | LogLevel      | ()                    | "template/log/level/set"      |
+    | GetLogLevelEndpoint       | ()            | LogLevel              | "template/log/level/get"      |
+    | OtaBeginEndpoint          | OtaBegin      | OtaResult             | "template/ota/begin"          |
+    | OtaWriteEndpoint          | OtaChunk<'a>  | OtaResult             | "template/ota/write"          |
+    | OtaFinishEndpoint         | ()            | OtaResult             | "template/ota/finish"         |
+    | ConfirmFirmwareEndpoint   | ()            | ()                    | "template/ota/confirm"        |
+    | SelfTestEndpoint          | ()            | SelfTestReport        | "template/selftest"           |
+    | GetDisplayStatusEndpoint  | ()            | DisplayStatus         | "template/display/status"     |
+    | SetNameEndpoint           | DeviceName    | ()                    | "template/name/set"           |
+    | GetNameEndpoint           | ()            | DeviceName            | "template/name/get"           |
+    | SetTimeEndpoint           | TimeSync      | ()                    | "template/time/set"           |
+    | NotifyEndpoint            | Notification  | bool                  | "template/notify"             |
+    | ShowMetricEndpoint        | MetricChoice  | Metric                | "template/metric/show"        |
+    | ShowQrCodeEndpoint        | QrCode        | bool                  | "template/qr/show"            |
 }
 
 // incoming topics handled by our device
@@ -61,4 +435,6 @@ topics! {
     direction = TopicDirection::ToClient;
     | TopicTy                   | MessageTy     | Path              | Cfg                           |
     | -------                   | ---------     | ----              | ---                           |
+    | SettingsChangedTopic      | DeviceSettings | "template/settings/changed" |                      |
+    | LogTopic                  | LogRecord     | "template/log"    |                               |
 }
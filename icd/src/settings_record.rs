@@ -0,0 +1,269 @@
+//! How the device keeps [`DeviceSettings`] in flash. It lives here rather than in the firmware so
+//! reading records of older firmware can be tested on the host.
+//!
+//! A record starts with a magic, the length of the postcard encoded settings and the [`VERSION`]
+//! that wrote them. Fields are only ever added at the end, so a record of an older version is read
+//! by appending the defaults of the fields it is missing.
+
+use crate::DeviceSettings;
+
+/// Only the start of the flash sector is used, one flash page is plenty for the settings
+pub const RECORD_SIZE: usize = 256;
+/// Bumped with every change to [`DeviceSettings`], see [`append_new_fields`] for what each adds
+pub const VERSION: u16 = 9;
+/// Marks a record with a version. Erased flash reads back as all 0xFF
+const MAGIC: u32 = 0x5E77_1266;
+/// Marks records from before the header had a version, those could be any version up to 9
+const UNVERSIONED_MAGIC: u32 = 0x5E77_1265;
+/// Magic (4 bytes), length of the postcard encoded settings (2 bytes) and version (2 bytes)
+const HEADER_SIZE: usize = 8;
+const UNVERSIONED_HEADER_SIZE: usize = 6;
+
+/// The record for `settings` at the current [`VERSION`], the unused rest is left erased
+pub fn encode(settings: &DeviceSettings) -> [u8; RECORD_SIZE] {
+    let mut record = [0xFFu8; RECORD_SIZE];
+    record[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    //A DeviceSettings always fits in the record, so this is only here to not panic
+    let used = postcard::to_slice(settings, &mut record[HEADER_SIZE..])
+        .map(|used| used.len())
+        .unwrap_or(0);
+    record[4..6].copy_from_slice(&(used as u16).to_le_bytes());
+    record[6..HEADER_SIZE].copy_from_slice(&VERSION.to_le_bytes());
+    record
+}
+
+/// Whether nothing was ever written, as opposed to a record that could not be read
+pub fn is_erased(record: &[u8; RECORD_SIZE]) -> bool {
+    record[..4] == [0xFF; 4]
+}
+
+/// The settings in `record` and the version that wrote them
+pub fn decode(record: &[u8; RECORD_SIZE]) -> Option<(u16, DeviceSettings)> {
+    let magic = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+    let len = u16::from_le_bytes([record[4], record[5]]) as usize;
+    match magic {
+        MAGIC => {
+            let version = u16::from_le_bytes([record[6], record[7]]);
+            let body = record.get(HEADER_SIZE..HEADER_SIZE + len)?;
+            Some((version, upgrade(body, version)?))
+        }
+        //The newest version that uses up the whole body wrote it, the tests check this holds for every version
+        UNVERSIONED_MAGIC => {
+            let body = record.get(UNVERSIONED_HEADER_SIZE..UNVERSIONED_HEADER_SIZE + len)?;
+            (1..=VERSION)
+                .rev()
+                .find_map(|version| Some((version, upgrade(body, version)?)))
+        }
+        _ => None,
+    }
+}
+
+/// Reads `body` as settings of `version`, if all of it is used
+fn upgrade(body: &[u8], version: u16) -> Option<DeviceSettings> {
+    let mut buf = [0u8; RECORD_SIZE];
+    buf.get_mut(..body.len())?.copy_from_slice(body);
+    let len = append_new_fields(version, &mut buf, body.len())?;
+    match postcard::take_from_bytes(&buf[..len]) {
+        Ok((settings, [])) => Some(settings),
+        _ => None,
+    }
+}
+
+/// Appends the defaults of the fields added after `version` to the `len` bytes in `buf`.
+/// Returns the new length, or None for a version this firmware does not know
+fn append_new_fields(version: u16, buf: &mut [u8], mut len: usize) -> Option<usize> {
+    if version == 0 || version > VERSION {
+        return None;
+    }
+    let defaults = DeviceSettings::default();
+    for added in version + 1..=VERSION {
+        let rest = buf.get_mut(len..)?;
+        let used = match added {
+            2 => postcard::to_slice(&defaults.buzzer_muted, rest),
+            3 => postcard::to_slice(&defaults.name, rest),
+            4 => postcard::to_slice(&defaults.show_clock, rest),
+            5 => postcard::to_slice(&defaults.multi_host, rest),
+            6 => postcard::to_slice(&defaults.i2c_speed, rest),
+            7 => postcard::to_slice(&defaults.theme, rest),
+            8 => postcard::to_slice(&defaults.layout, rest),
+            9 => postcard::to_slice(&defaults.peak_hold, rest),
+            _ => return None,
+        };
+        len += used.ok()?.len();
+    }
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Brightness, I2cSpeed, Layout, MultiHostLayout, Page, Rgb, Rotation, Theme};
+    use serde::Serialize;
+
+    /// Fields in a record of each version, version 1 had the first five
+    const FIELDS: [usize; VERSION as usize] = [5, 6, 7, 8, 9, 10, 11, 12, 13];
+
+    /// Settings with every field away from its default, so a field read from the wrong place
+    /// shows up
+    fn changed() -> DeviceSettings {
+        DeviceSettings {
+            brightness: Brightness::Dim,
+            rotation: Rotation::Flipped,
+            page_order: [Page::Memory, Page::Overview, Page::Cpu],
+            cpu_alert: 70,
+            memory_alert: 0,
+            buzzer_muted: true,
+            name: "desk".try_into().unwrap(),
+            show_clock: false,
+            multi_host: MultiHostLayout::SideBySide,
+            i2c_speed: I2cSpeed::FastPlus,
+            theme: Theme {
+                background: Rgb { r: 1, g: 2, b: 3 },
+                ..Theme::DEFAULT
+            },
+            layout: Layout::Gauges,
+            peak_hold: false,
+        }
+    }
+
+    /// What a record of `version` written with [`changed`] reads back as
+    fn expected(version: u16) -> DeviceSettings {
+        let changed = changed();
+        let defaults = DeviceSettings::default();
+        let fields = FIELDS[version as usize - 1];
+        DeviceSettings {
+            buzzer_muted: if fields > 5 {
+                changed.buzzer_muted
+            } else {
+                defaults.buzzer_muted
+            },
+            name: if fields > 6 {
+                changed.name.clone()
+            } else {
+                defaults.name
+            },
+            show_clock: if fields > 7 {
+                changed.show_clock
+            } else {
+                defaults.show_clock
+            },
+            multi_host: if fields > 8 {
+                changed.multi_host
+            } else {
+                defaults.multi_host
+            },
+            i2c_speed: if fields > 9 {
+                changed.i2c_speed
+            } else {
+                defaults.i2c_speed
+            },
+            theme: if fields > 10 {
+                changed.theme
+            } else {
+                defaults.theme
+            },
+            layout: if fields > 11 {
+                changed.layout
+            } else {
+                defaults.layout
+            },
+            peak_hold: if fields > 12 {
+                changed.peak_hold
+            } else {
+                defaults.peak_hold
+            },
+            ..changed
+        }
+    }
+
+    fn push(body: &mut [u8], len: &mut usize, field: &impl Serialize) {
+        *len += postcard::to_slice(field, &mut body[*len..]).unwrap().len();
+    }
+
+    /// The settings as the firmware of `version` encoded them: the same as the current ones,
+    /// cut off after the fields it had
+    fn body(version: u16) -> ([u8; RECORD_SIZE], usize) {
+        let s = changed();
+        let mut body = [0u8; RECORD_SIZE];
+        let mut len = 0;
+        let fields = FIELDS[version as usize - 1];
+        push(&mut body, &mut len, &s.brightness);
+        push(&mut body, &mut len, &s.rotation);
+        push(&mut body, &mut len, &s.page_order);
+        push(&mut body, &mut len, &s.cpu_alert);
+        push(&mut body, &mut len, &s.memory_alert);
+        for field in 6..=fields {
+            match field {
+                6 => push(&mut body, &mut len, &s.buzzer_muted),
+                7 => push(&mut body, &mut len, &s.name),
+                8 => push(&mut body, &mut len, &s.show_clock),
+                9 => push(&mut body, &mut len, &s.multi_host),
+                10 => push(&mut body, &mut len, &s.i2c_speed),
+                11 => push(&mut body, &mut len, &s.theme),
+                12 => push(&mut body, &mut len, &s.layout),
+                _ => push(&mut body, &mut len, &s.peak_hold),
+            }
+        }
+        (body, len)
+    }
+
+    fn record(magic: u32, version: Option<u16>, body: &[u8]) -> [u8; RECORD_SIZE] {
+        let mut record = [0xFFu8; RECORD_SIZE];
+        record[..4].copy_from_slice(&magic.to_le_bytes());
+        record[4..6].copy_from_slice(&(body.len() as u16).to_le_bytes());
+        let start = match version {
+            Some(version) => {
+                record[6..8].copy_from_slice(&version.to_le_bytes());
+                HEADER_SIZE
+            }
+            None => UNVERSIONED_HEADER_SIZE,
+        };
+        record[start..start + body.len()].copy_from_slice(body);
+        record
+    }
+
+    #[test]
+    fn current_record_reads_back() {
+        let settings = changed();
+        assert_eq!(decode(&encode(&settings)), Some((VERSION, settings)));
+    }
+
+    #[test]
+    fn every_version_carries_over() {
+        for version in 1..=VERSION {
+            let (body, len) = body(version);
+            let versioned = record(MAGIC, Some(version), &body[..len]);
+            assert_eq!(
+                decode(&versioned),
+                Some((version, expected(version))),
+                "version {version}"
+            );
+        }
+    }
+
+    #[test]
+    fn every_unversioned_record_carries_over() {
+        for version in 1..=VERSION {
+            let (body, len) = body(version);
+            let unversioned = record(UNVERSIONED_MAGIC, None, &body[..len]);
+            assert_eq!(
+                decode(&unversioned),
+                Some((version, expected(version))),
+                "version {version}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_versions_and_erased_flash_are_not_read() {
+        let (body, len) = body(VERSION);
+        assert_eq!(
+            decode(&record(MAGIC, Some(VERSION + 1), &body[..len])),
+            None
+        );
+        assert_eq!(decode(&record(MAGIC, Some(0), &body[..len])), None);
+        let erased = [0xFF; RECORD_SIZE];
+        assert!(is_erased(&erased));
+        assert_eq!(decode(&erased), None);
+    }
+}